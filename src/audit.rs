@@ -0,0 +1,101 @@
+//! The exec audit log: one JSON line per `/v1/exec` request, appended to
+//! `server.audit_log`.
+
+use super::reaper::Exit;
+use log::error;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize, Debug, Clone)]
+struct AuditEntry {
+    /// When the request arrived, in milliseconds since the Unix epoch.
+    started_at_ms: u128,
+    duration_ms: u128,
+    cmd: Vec<String>,
+    /// Whether the request had to present the API token.
+    authenticated: bool,
+    exit_code: Option<i32>,
+    signal: Option<i32>,
+    timed_out: bool,
+    /// Why the command didn't run to completion, if it didn't.
+    error: Option<String>,
+}
+
+/// The audit record of one exec request, written when it is dropped so that requests that
+/// fail, or are abandoned by the client, are recorded too.
+pub struct ExecAudit {
+    path: Option<String>,
+    started: Instant,
+    entry: AuditEntry,
+}
+
+impl ExecAudit {
+    pub fn start(path: Option<String>, cmd: &[String], authenticated: bool) -> ExecAudit {
+        let started_at_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        ExecAudit {
+            path,
+            started: Instant::now(),
+            entry: AuditEntry {
+                started_at_ms,
+                duration_ms: 0,
+                cmd: cmd.to_vec(),
+                authenticated,
+                exit_code: None,
+                signal: None,
+                timed_out: false,
+                error: Some("the request was abandoned".to_string()),
+            },
+        }
+    }
+
+    pub fn exited(&mut self, exit: &Exit, timed_out: bool) {
+        self.entry.exit_code = exit.code;
+        self.entry.signal = exit.signal;
+        self.entry.timed_out = timed_out;
+        self.entry.error = None;
+    }
+
+    pub fn failed(&mut self, message: &str) {
+        self.entry.error = Some(message.to_string());
+    }
+
+    /// Records an exec request turned away before it ran: a bad or missing token, the rate
+    /// limit, or a body that didn't parse. What command it asked for isn't known by then.
+    pub fn rejected(path: Option<String>, authenticated: bool, message: &str) {
+        ExecAudit::start(path, &[], authenticated).failed(message);
+    }
+
+    /// Moves the record out, for a command that outlives its request's handler (a streamed
+    /// download), leaving behind one that writes nothing.
+    pub fn take(&mut self) -> ExecAudit {
+        ExecAudit {
+            path: self.path.take(),
+            started: self.started,
+            entry: self.entry.clone(),
+        }
+    }
+}
+
+impl Drop for ExecAudit {
+    fn drop(&mut self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        self.entry.duration_ms = self.started.elapsed().as_millis();
+        let mut line = serde_json::to_vec(&self.entry).unwrap_or_default();
+        line.push(b'\n');
+        let res = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(&line));
+        if let Err(e) = res {
+            error!("Failed to write to the audit log {}: {}", path, e);
+        }
+    }
+}
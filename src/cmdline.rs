@@ -0,0 +1,130 @@
+use super::config::{IpConfig, RunConfig};
+use super::is_pid1;
+use log::LevelFilter;
+use nix::mount::{mount, umount, MsFlags};
+use nix::sys::stat::Mode;
+use nix::unistd::mkdir;
+use std::fs::read_to_string;
+
+const PREFIX: &str = "init.";
+
+/// Settings read from `init.`-prefixed `key=value` pairs on the kernel command line.
+/// These override the matching values from run.json for the current boot.
+#[derive(Debug, Default)]
+pub struct KernelCmdline {
+    pub ip: Option<String>,
+    pub gateway: Option<String>,
+    pub hostname: Option<String>,
+    pub log_level: Option<LevelFilter>,
+    pub config_path: Option<String>,
+    /// `init.` keys that weren't recognized, kept so they can be logged once logging is up.
+    pub unknown: Vec<String>,
+}
+
+impl KernelCmdline {
+    /// Reads /proc/cmdline. This runs before the real /proc is mounted (the log level has to be
+    /// known before the logger starts), so procfs is mounted temporarily when needed.
+    pub fn read() -> KernelCmdline {
+        if let Ok(raw) = read_to_string("/proc/cmdline") {
+            return KernelCmdline::parse(&raw);
+        }
+        if !is_pid1() {
+            return KernelCmdline::default();
+        }
+        mkdir("/proc", Mode::from_bits_truncate(0o555)).ok();
+        if mount(
+            Some("proc"),
+            "/proc",
+            Some("proc"),
+            MsFlags::empty(),
+            None::<&str>,
+        )
+        .is_err()
+        {
+            return KernelCmdline::default();
+        }
+        let raw = read_to_string("/proc/cmdline").unwrap_or_default();
+        umount("/proc").ok();
+        KernelCmdline::parse(&raw)
+    }
+
+    pub fn parse(raw: &str) -> KernelCmdline {
+        let mut cmdline = KernelCmdline::default();
+        for arg in split_args(raw) {
+            let Some(setting) = arg.strip_prefix(PREFIX) else {
+                continue;
+            };
+            let Some((key, value)) = setting.split_once('=') else {
+                cmdline.unknown.push(arg);
+                continue;
+            };
+            let value = value.to_string();
+            match key {
+                "ip" => cmdline.ip = Some(value),
+                "gateway" => cmdline.gateway = Some(value),
+                "hostname" => cmdline.hostname = Some(value),
+                "log" => match value.parse() {
+                    Ok(level) => cmdline.log_level = Some(level),
+                    Err(_) => cmdline.unknown.push(arg),
+                },
+                "config" => cmdline.config_path = Some(value),
+                _ => cmdline.unknown.push(arg),
+            }
+        }
+        cmdline
+    }
+
+    pub fn apply(&self, run_config: &mut RunConfig) {
+        if let Some(hostname) = &self.hostname {
+            run_config.hostname = Some(hostname.clone());
+        }
+
+        if self.ip.is_none() && self.gateway.is_none() {
+            return;
+        }
+        let ip_configs = run_config.ip_configs.get_or_insert_with(Vec::new);
+        let existing = ip_configs.first().cloned();
+        let ip = match self.ip.clone().or(existing.as_ref().map(|c| c.ip.clone())) {
+            Some(ip) => ip,
+            None => return,
+        };
+        let mask = ip
+            .split_once('/')
+            .and_then(|(_, prefix)| prefix.parse().ok())
+            .or(existing.as_ref().map(|c| c.mask))
+            .unwrap_or(24);
+        let gateway = self
+            .gateway
+            .clone()
+            .or(existing.map(|c| c.gateway))
+            .unwrap_or_default();
+
+        let ip_config = IpConfig { ip, gateway, mask };
+        match ip_configs.first_mut() {
+            Some(first) => *first = ip_config,
+            None => ip_configs.push(ip_config),
+        }
+    }
+}
+
+// Splits on whitespace, keeping double-quoted sections (`key="a b"`) together.
+fn split_args(raw: &str) -> Vec<String> {
+    let mut args = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in raw.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    args.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        args.push(current);
+    }
+    args
+}
@@ -0,0 +1,1202 @@
+use super::ops;
+use super::process;
+use super::state::SharedState;
+use anyhow::{anyhow, bail, Context, Error};
+use log::{info, warn, LevelFilter};
+use nix::sys::signal::Signal;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::env;
+use std::fs::read_to_string;
+use std::net::IpAddr;
+use std::path::Path;
+
+pub const RUN_CONFIG_PATH: &str = "/firestarter/run.json";
+const REDACTED: &str = "[redacted]";
+
+/// Where to read the run config from: `init.config` on the kernel command line, then the
+/// `INIT_CONFIG` environment variable (handy when running outside a VM), then the default.
+pub fn run_config_path(cmdline_path: Option<&str>) -> String {
+    cmdline_path
+        .map(String::from)
+        .or_else(|| env::var("INIT_CONFIG").ok())
+        .unwrap_or_else(|| RUN_CONFIG_PATH.to_string())
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct FileConfig {
+    pub guest_path: String,
+    pub raw_value: String,
+    #[serde(default)]
+    pub encoding: FileEncoding,
+    /// Permissions set on the file after writing. This is applied with chmod, so unlike the
+    /// permissions of files created without it, it isn't reduced by the configured umask.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_mode",
+        serialize_with = "serialize_mode"
+    )]
+    pub mode: Option<u32>,
+    /// Append to the file instead of replacing it. Nothing is appended when the file already
+    /// ends with these contents, so writing the files again doesn't repeat the append.
+    #[serde(default)]
+    pub append: bool,
+    /// When false, a file that already exists is left as it is, so a default can be seeded
+    /// once without clobbering later edits (on a persistent volume, say).
+    #[serde(default = "default_overwrite")]
+    pub overwrite: bool,
+    /// Stop if the file can't be written (including when `raw_value` isn't valid base64).
+    /// Otherwise the failure is logged and the remaining files are still written.
+    #[serde(default)]
+    pub required: bool,
+    /// Write the file again when the run config is reloaded. Other files are only written at
+    /// boot.
+    #[serde(default)]
+    pub reloadable: bool,
+}
+
+fn default_overwrite() -> bool {
+    true
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CollisionPolicy {
+    /// Log the conflicting entries and carry on.
+    #[default]
+    Warn,
+    /// Refuse the config.
+    Error,
+}
+
+/// How a file's `raw_value` is encoded. Padding is optional for both.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileEncoding {
+    /// The standard alphabet, with `+` and `/`.
+    #[default]
+    Base64,
+    /// The URL- and filename-safe alphabet, with `-` and `_`.
+    Base64Url,
+}
+
+fn serialize_mode<S>(mode: &Option<u32>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match mode {
+        Some(m) => serializer.serialize_str(&format!("{:04o}", m)),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// A file mode written either as an octal string (`"0644"`) or as a plain JSON number.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawMode {
+    Octal(String),
+    Number(u32),
+}
+
+impl RawMode {
+    fn parse<E: serde::de::Error>(self) -> Result<u32, E> {
+        match self {
+            RawMode::Number(n) => Ok(n),
+            RawMode::Octal(s) => u32::from_str_radix(s.trim_start_matches("0o"), 8)
+                .map_err(|_| E::custom(format!("invalid octal mode {:?}", s))),
+        }
+    }
+}
+
+fn deserialize_mode<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<RawMode>::deserialize(deserializer)?
+        .map(RawMode::parse)
+        .transpose()
+}
+
+fn serialize_mode_map<S>(modes: &HashMap<String, u32>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_map(modes.iter().map(|(path, m)| (path, format!("{:04o}", m))))
+}
+
+fn deserialize_mode_map<'de, D>(deserializer: D) -> Result<HashMap<String, u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    HashMap::<String, RawMode>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(path, mode)| Ok((path, mode.parse()?)))
+        .collect()
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct IpConfig {
+    #[serde(rename = "IP")]
+    pub ip: String,
+    #[serde(rename = "Gateway")]
+    pub gateway: String,
+    #[serde(rename = "Mask")]
+    pub mask: u8,
+}
+
+impl IpConfig {
+    pub fn address(&self) -> Result<IpAddr, Error> {
+        parse_addr(&self.ip)
+    }
+
+    pub fn gateway(&self) -> Result<IpAddr, Error> {
+        parse_addr(&self.gateway)
+    }
+}
+
+// run.json addresses may carry a "/prefix" suffix; the prefix length comes from `Mask`.
+fn parse_addr(s: &str) -> Result<IpAddr, Error> {
+    let addr = s.split('/').next().unwrap_or(s);
+    addr.parse()
+        .map_err(|e| anyhow!("invalid address {:?}: {}", s, e))
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct EtcResolv {
+    #[serde(rename = "Nameservers")]
+    pub nameservers: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct EtcHost {
+    #[serde(rename = "Host")]
+    pub host: String,
+    #[serde(rename = "IP")]
+    pub ip: String,
+    #[serde(rename = "Desc")]
+    pub desc: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ImageConfig {
+    #[serde(rename = "Entrypoint")]
+    pub entrypoint: Option<Vec<String>>,
+    #[serde(rename = "Cmd")]
+    pub cmd: Option<Vec<String>>,
+    #[serde(rename = "Env")]
+    pub env: Option<Vec<String>>,
+    #[serde(rename = "WorkingDir")]
+    pub working_dir: Option<String>,
+    #[serde(rename = "User")]
+    pub user: Option<String>,
+}
+
+/// Process settings for the entrypoint that aren't part of the image config.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct EntrypointConfig {
+    /// Niceness (-20 to 19) to run the entrypoint at.
+    pub nice: Option<i32>,
+    /// CPUs (by index, from 0) to pin the entrypoint to, e.g. `[2, 3]` to leave 0 and 1 to
+    /// everything else. Ones the guest doesn't have are logged and ignored.
+    pub cpu_affinity: Option<BTreeSet<usize>>,
+    /// Until this passes, `/v1/health` reports the guest as not ready.
+    pub readiness_probe: Option<ProbeConfig>,
+    /// Terminal (e.g. `/dev/console`) to give the entrypoint as its controlling terminal and
+    /// stdio, for shells and other programs that need job control.
+    pub tty: Option<String>,
+    /// `.env`-style file in the guest to read environment variables from. `ExtraEnv` still
+    /// takes precedence over it.
+    pub env_file: Option<String>,
+    /// When to stop the entrypoint at shutdown relative to other supervised processes: lower
+    /// values are stopped first, and equal ones together.
+    pub shutdown_priority: i32,
+    /// How long the entrypoint gets to exit after SIGTERM before it is killed. Defaults to 10
+    /// seconds.
+    pub grace_period_ms: Option<u64>,
+}
+
+/// A process supervised alongside the entrypoint, such as a log shipper or metrics agent. It
+/// gets the workload's environment plus `env`, and a session of its own.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SidecarConfig {
+    /// Identifies the sidecar in logs and `/v1/health`; must be unique.
+    pub name: String,
+    pub cmd: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    pub working_dir: Option<String>,
+    /// User to run as. Defaults to root.
+    pub user: Option<String>,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+    /// Until this passes, `/v1/health` reports the guest as not ready.
+    pub readiness_probe: Option<ProbeConfig>,
+    /// See `EntrypointConfig::shutdown_priority`.
+    #[serde(default)]
+    pub shutdown_priority: i32,
+    pub grace_period_ms: Option<u64>,
+    /// The signal that asks it to stop, at shutdown or a `POST /v1/process/:name/stop`, e.g.
+    /// `"SIGINT"` or `"QUIT"`. It's killed once the grace period passes either way.
+    #[serde(
+        default = "default_stop_signal",
+        deserialize_with = "deserialize_signal",
+        serialize_with = "serialize_signal"
+    )]
+    pub stop_signal: Signal,
+}
+
+fn default_stop_signal() -> Signal {
+    Signal::SIGTERM
+}
+
+fn serialize_signal<S>(signal: &Signal, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(signal.as_str())
+}
+
+/// A signal name, with or without the `SIG` prefix.
+fn deserialize_signal<'de, D>(deserializer: D) -> Result<Signal, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let name = String::deserialize(deserializer)?;
+    let full = match name.to_uppercase() {
+        upper if upper.starts_with("SIG") => upper,
+        upper => format!("SIG{}", upper),
+    };
+    full.parse()
+        .map_err(|_| serde::de::Error::custom(format!("unknown signal {:?}", name)))
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    Never,
+    /// Restart after a non-zero exit or a signal.
+    #[default]
+    OnFailure,
+    Always,
+}
+
+/// A check that the workload is up: either a command that must exit 0 (run with the
+/// entrypoint's environment) or a TCP address that must accept a connection.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ProbeConfig {
+    pub command: Option<Vec<String>>,
+    pub tcp: Option<String>,
+    #[serde(default = "default_probe_interval_ms")]
+    pub interval_ms: u64,
+    #[serde(default = "default_probe_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Consecutive passes needed.
+    #[serde(default = "default_probe_success_threshold")]
+    pub success_threshold: u32,
+}
+
+fn default_probe_interval_ms() -> u64 {
+    1000
+}
+
+fn default_probe_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_probe_success_threshold() -> u32 {
+    1
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceType {
+    #[serde(alias = "c")]
+    Char,
+    #[serde(alias = "b")]
+    Block,
+}
+
+/// A device node to create in /dev when devtmpfs doesn't provide it.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DeviceConfig {
+    pub path: String,
+    #[serde(rename = "type")]
+    pub kind: DeviceType,
+    pub major: u64,
+    pub minor: u64,
+    /// Defaults to 0666.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_mode",
+        serialize_with = "serialize_mode"
+    )]
+    pub mode: Option<u32>,
+}
+
+/// A data disk to mount once the root filesystem is ready.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct VolumeConfig {
+    pub device: String,
+    pub mountpoint: String,
+    #[serde(default = "default_volume_fstype")]
+    pub fstype: String,
+    /// Check the filesystem with `fsck.<fstype>` before mounting it.
+    #[serde(default)]
+    pub fsck: bool,
+    /// Create a filesystem of `fstype` with `mkfs.<fstype>` if the device is blank, for fresh
+    /// disks. Only a device whose first and last MiB are all zeroes counts as blank, so one
+    /// with any data on it, recognized or not, is never formatted.
+    #[serde(default)]
+    pub format_if_empty: bool,
+    /// Comma-separated mount options, e.g. `"noatime,discard"`.
+    pub options: Option<String>,
+    /// Stop booting if the volume fails its check or can't be mounted. Otherwise the failure
+    /// is logged and boot carries on without the volume.
+    #[serde(default)]
+    pub required: bool,
+    /// Reformat the volume before mounting it, destroying everything on it, for resetting a
+    /// stateful guest.
+    pub wipe: Option<WipeConfig>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct WipeConfig {
+    /// Must repeat the volume's `device` for the wipe to go ahead.
+    pub confirm: String,
+    /// Names this reset (a timestamp, say). It's recorded on the fresh filesystem, and a
+    /// volume already wiped under it isn't wiped again, so a flag left set doesn't wipe the
+    /// volume at every boot.
+    pub id: String,
+}
+
+fn default_volume_fstype() -> String {
+    "ext4".to_string()
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RootCheckConfig {
+    /// Paths in the root filesystem that must exist (e.g. `["/etc", "/usr/bin"]`), besides
+    /// the root directory being listable and statfs succeeding.
+    pub expect_paths: Vec<String>,
+    /// When the check fails, stay in the initramfs and run only the control API so the host
+    /// can investigate, instead of failing boot. `/v1/health` reports the problem.
+    pub recovery: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RootImageConfig {
+    /// Device or file holding the archive.
+    #[serde(default = "default_root_image_source")]
+    pub source: String,
+    #[serde(default)]
+    pub format: RootImageFormat,
+}
+
+fn default_root_image_source() -> String {
+    "/dev/vdb".to_string()
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum RootImageFormat {
+    /// A zstd-compressed tarball.
+    #[default]
+    TarZstd,
+    TarGzip,
+    Tar,
+}
+
+/// Toggles for the default pseudo filesystem mounts. Everything is mounted unless turned off,
+/// except the optional extras.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct MountsConfig {
+    /// On kernels without devtmpfs, mount a tmpfs on /dev and create the essential device
+    /// nodes (and one for each block device) in it, instead of failing boot.
+    pub dev_fallback: bool,
+    pub dev_pts: bool,
+    pub dev_mqueue: bool,
+    pub dev_shm: bool,
+    pub run: bool,
+    pub binfmt_misc: bool,
+    /// When set, `/var/log` is a tmpfs capped at this size (as accepted by tmpfs' `size=`,
+    /// e.g. `"64m"`), so logs can't fill the root filesystem. Off by default.
+    pub var_log_size: Option<String>,
+    /// Permissions for mount point directories that init creates, keyed by path (e.g.
+    /// `{"/proc": "0555"}`). Paths not listed get the usual defaults.
+    #[serde(
+        deserialize_with = "deserialize_mode_map",
+        serialize_with = "serialize_mode_map"
+    )]
+    pub dir_modes: HashMap<String, u32>,
+    /// Access time behaviour per mount point (e.g. `{"/": "noatime"}`, where `/` is the root
+    /// filesystem). Mounts not listed keep their usual flags. Data volumes take theirs from
+    /// `options` instead.
+    pub atime: HashMap<String, Atime>,
+    /// Hide other users' processes in /proc.
+    pub proc_hidepid: Option<HidePid>,
+    /// Group whose members still see every process when `proc_hidepid` is set.
+    pub proc_gid: Option<u32>,
+    /// Whether to mount the cgroup v2 hierarchy on /sys/fs/cgroup. Not mounted by default.
+    pub cgroup: CgroupMount,
+    /// Kernel debugging filesystems, for investigating the guest's kernel: debugfs on
+    /// /sys/kernel/debug, tracefs on /sys/kernel/tracing and configfs on /sys/kernel/config.
+    /// Off by default, since debugfs and tracefs expose kernel internals to root in the
+    /// guest.
+    pub debugfs: bool,
+    pub tracefs: bool,
+    pub configfs: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CgroupMount {
+    #[default]
+    Skip,
+    /// Read-only, for workloads that only inspect their limits and usage.
+    Ro,
+    /// Writable, for a process manager in the guest that creates cgroups of its own.
+    Rw,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HidePid {
+    /// Everyone can see every process (the kernel's default).
+    Off,
+    /// Processes are listed, but other users' details can't be read.
+    Noaccess,
+    /// Other users' processes aren't listed at all.
+    Invisible,
+    /// Only processes the user could ptrace are visible. Needs Linux 5.8 or later.
+    Ptraceable,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Atime {
+    /// Update access times only when they're older than the modification time.
+    Relatime,
+    Noatime,
+    /// Update access times on every access.
+    Strictatime,
+}
+
+impl Default for MountsConfig {
+    fn default() -> Self {
+        MountsConfig {
+            dev_fallback: true,
+            dev_pts: true,
+            dev_mqueue: true,
+            dev_shm: true,
+            run: true,
+            binfmt_misc: true,
+            var_log_size: None,
+            dir_modes: HashMap::new(),
+            atime: HashMap::new(),
+            proc_hidepid: None,
+            proc_gid: None,
+            cgroup: CgroupMount::Skip,
+            debugfs: false,
+            tracefs: false,
+            configfs: false,
+        }
+    }
+}
+
+/// How networking is brought up at boot.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct NetworkConfig {
+    pub attempts: u32,
+    /// Delay before the first retry, doubled after each further failure.
+    pub initial_backoff_ms: u64,
+    /// MAC address to give eth0, as `aa:bb:cc:dd:ee:ff`. By default the VMM's is kept.
+    pub mac: Option<String>,
+    /// Default gateways, overriding the ones from `IPConfigs`. Without either, each family
+    /// routes via the first of its addresses that has a gateway, or gets no default route.
+    pub gateway_v4: Option<String>,
+    pub gateway_v6: Option<String>,
+    /// Enable IPv4 and IPv6 forwarding, for guests that route for others. Set before the
+    /// interfaces come up.
+    pub ip_forward: bool,
+    /// Interface to masquerade forwarded IPv4 traffic behind, using iptables from the image.
+    pub masquerade: Option<String>,
+    /// Transmit queue length for eth0. The driver's default is kept otherwise.
+    pub txqueuelen: Option<u32>,
+    /// Offloads to turn on or off on eth0 (e.g. `{"gro": false}`). Ones not listed are left
+    /// as the driver sets them. A setting the driver rejects is logged and skipped.
+    pub offloads: BTreeMap<Offload, bool>,
+    /// Ping the default gateways once networking is up. Networking only counts as up (in the
+    /// boot report) if they answer.
+    pub gateway_check: Option<GatewayCheck>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GatewayCheck {
+    /// Pings sent to each gateway; one reply is enough.
+    #[serde(default = "default_ping_count")]
+    pub count: u16,
+    /// How long to wait for each reply.
+    #[serde(default = "default_ping_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Keep checking until every gateway answers, holding up the rest of boot (and
+    /// readiness) until then.
+    #[serde(default)]
+    pub wait: bool,
+}
+
+fn default_ping_count() -> u16 {
+    3
+}
+
+fn default_ping_timeout_ms() -> u64 {
+    1000
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Offload {
+    /// Generic receive offload.
+    Gro,
+    /// Generic segmentation offload.
+    Gso,
+    /// TCP segmentation offload.
+    Tso,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            attempts: 5,
+            initial_backoff_ms: 200,
+            mac: None,
+            gateway_v4: None,
+            gateway_v6: None,
+            ip_forward: false,
+            masquerade: None,
+            txqueuelen: None,
+            offloads: BTreeMap::new(),
+            gateway_check: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Vsock,
+    Tcp,
+    /// A Unix domain socket at `unix_path`, for an agent running inside the guest.
+    Unix,
+}
+
+/// Mutual TLS for the control API, as PEM. Connections without a client certificate signed
+/// by `client_ca` are rejected.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+    pub client_ca: String,
+}
+
+/// Token bucket limit for `POST /v1/exec`: up to `burst` requests at once, refilled at
+/// `per_second`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            per_second: 20.0,
+            burst: 50,
+        }
+    }
+}
+
+/// A one-shot boot report, sent once init is ready: a line of JSON written to a vsock port
+/// on the host, to a file, or both.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct NotifyConfig {
+    pub vsock_port: Option<u32>,
+    /// Defaults to the host.
+    #[serde(default = "default_notify_cid")]
+    pub vsock_cid: u32,
+    pub path: Option<String>,
+}
+
+fn default_notify_cid() -> u32 {
+    libc::VMADDR_CID_HOST
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SighupAction {
+    #[default]
+    Reload,
+    Ignore,
+}
+
+/// A file that exists while init is ready, for tooling in the guest that waits on a path
+/// rather than polling the API. It's removed at shutdown.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ReadyFileConfig {
+    pub path: String,
+    #[serde(default)]
+    pub contents: ReadyFileContents,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadyFileContents {
+    #[default]
+    Empty,
+    /// When init became ready, in seconds since the Unix epoch.
+    Timestamp,
+    /// The boot report `notify` sends, as a line of JSON.
+    Report,
+}
+
+/// A command run at shutdown, before the workload is stopped (e.g. to deregister from a load
+/// balancer). It gets the workload's environment and runs as root.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct HookConfig {
+    pub cmd: Vec<String>,
+    /// After this long the hook and anything it started are killed, and shutdown goes on.
+    #[serde(default = "default_hook_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_reap_log_level() -> LevelFilter {
+    LevelFilter::Debug
+}
+
+fn default_subreaper() -> bool {
+    true
+}
+
+fn default_stuck_after_ms() -> u64 {
+    60_000
+}
+
+fn default_hook_timeout_ms() -> u64 {
+    10_000
+}
+
+/// Where the control API listens. TCP is meant for development and integration testing
+/// outside a VM, where vsock isn't available; a Unix socket for a control plane inside the
+/// guest.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub transport: Transport,
+    pub vsock_port: u32,
+    pub tcp_address: String,
+    /// A stale socket left at this path is replaced.
+    pub unix_path: String,
+    /// Backlog of connections waiting to be accepted. Raise it if bursts of connections (an
+    /// orchestrator reconnecting, say) get refused.
+    pub listen_backlog: u32,
+    pub tls: Option<TlsConfig>,
+    /// When set, every API request must carry `Authorization: Bearer <auth_token>`.
+    pub auth_token: Option<String>,
+    pub exec_rate_limit: RateLimitConfig,
+    /// File in the guest to append a JSON line to for every `/v1/exec` request: the command,
+    /// when it ran, for how long and how it ended.
+    pub audit_log: Option<String>,
+    /// Serve these parts of the API on a port of their own (a vsock port, or with TCP a port
+    /// on `tcp_address`'s host) instead of the main one, e.g. `{"logs": 10001}`. Groups given
+    /// the same port share a listener. Not supported with the Unix transport.
+    pub ports: BTreeMap<ApiGroup, u32>,
+}
+
+/// Parts of the control API that can be moved off the main port.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiGroup {
+    /// `/v1/logs`, `/v1/tail`, `/v1/dmesg` and `/v1/events`.
+    Logs,
+    /// `/v1/sysinfo` and `/v1/disks`.
+    Metrics,
+}
+
+impl ApiGroup {
+    pub const ALL: [ApiGroup; 2] = [ApiGroup::Logs, ApiGroup::Metrics];
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            transport: Transport::Vsock,
+            vsock_port: 10000,
+            tcp_address: "127.0.0.1:10000".to_string(),
+            unix_path: "/run/init.sock".to_string(),
+            listen_backlog: 128,
+            tls: None,
+            auth_token: None,
+            exec_rate_limit: RateLimitConfig::default(),
+            audit_log: None,
+            ports: BTreeMap::new(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// `INIT_TRANSPORT` and `INIT_TCP_ADDRESS` take precedence over run.json.
+    pub fn apply_env(&mut self) -> Result<(), Error> {
+        if let Ok(transport) = env::var("INIT_TRANSPORT") {
+            self.transport = match transport.to_lowercase().as_str() {
+                "vsock" => Transport::Vsock,
+                "tcp" => Transport::Tcp,
+                "unix" => Transport::Unix,
+                other => return Err(anyhow!("unknown INIT_TRANSPORT {:?}", other)),
+            };
+        }
+        if let Ok(address) = env::var("INIT_TCP_ADDRESS") {
+            self.tcp_address = address;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RunConfig {
+    #[serde(rename = "ImageConfig", default)]
+    pub image_config: ImageConfig,
+    #[serde(rename = "ExecOverride")]
+    pub exec_override: Option<Vec<String>>,
+    #[serde(rename = "CmdOverride")]
+    pub cmd_override: Option<Vec<String>>,
+    #[serde(rename = "ExtraEnv")]
+    pub extra_env: Option<HashMap<String, String>>,
+    #[serde(rename = "UserOverride")]
+    pub user_override: Option<String>,
+    /// Environment for every process init starts (the entrypoint and exec requests), on top
+    /// of init's own. Giving this replaces the defaults rather than adding to them.
+    #[serde(default = "default_base_env")]
+    pub base_env: HashMap<String, String>,
+    #[serde(default)]
+    pub entrypoint: EntrypointConfig,
+    /// Command to exec into once setup is done, replacing init as PID 1 (it gets the same
+    /// environment the entrypoint would). Init's control API and supervision go away with it,
+    /// so the entrypoint isn't started.
+    pub init_cmd: Option<Vec<String>>,
+    #[serde(rename = "Hostname")]
+    pub hostname: Option<String>,
+    /// NIS domain name, for tools that still rely on it.
+    pub domainname: Option<String>,
+    #[serde(rename = "IPConfigs")]
+    pub ip_configs: Option<Vec<IpConfig>>,
+    #[serde(default)]
+    pub network: NetworkConfig,
+    #[serde(rename = "EtcResolv")]
+    pub etc_resolv: Option<EtcResolv>,
+    #[serde(rename = "EtcHosts")]
+    pub etc_hosts: Option<Vec<EtcHost>>,
+    /// Resolve names from /etc/hosts only, for guests with no DNS: resolv.conf gets no
+    /// nameserver and nsswitch.conf looks hosts up in files alone (replacing the image's).
+    /// Can't be combined with `EtcResolv` nameservers.
+    #[serde(default)]
+    pub hosts_only_dns: bool,
+    /// nsswitch.conf entries (database to sources, e.g. `{"hosts": "files dns"}`) that
+    /// replace or add to the defaults.
+    pub nsswitch: Option<BTreeMap<String, String>>,
+    /// PEM CA certificates for the guest to trust, added to its trust store at boot.
+    #[serde(default)]
+    pub ca_certs: Vec<String>,
+    /// Kernel parameters (`{"net.core.somaxconn": "1024"}`), set before networking is
+    /// brought up.
+    #[serde(default)]
+    pub sysctls: BTreeMap<String, String>,
+    #[serde(default)]
+    pub files: Vec<FileConfig>,
+    /// Write `files` as a set: each is written to a temporary file first, and only once all
+    /// of them are do they replace the originals. If any fails, none are changed and boot
+    /// stops, as if every file were `required`.
+    #[serde(default)]
+    pub transactional_files: bool,
+    /// Directory the `files` are written under: each `guest_path`, absolute or not, is taken
+    /// as relative to it, and one that would lead outside it is refused.
+    pub files_root: Option<String>,
+    /// What to do when more than one `files` entry replaces the same `guest_path` (the last
+    /// one would win). Entries with `append` set don't count.
+    #[serde(default)]
+    pub file_collisions: CollisionPolicy,
+    /// `(target, link)` pairs to create as symlinks.
+    #[serde(default)]
+    pub symlinks: Vec<(String, String)>,
+    /// fstab-style options for the ext4 root filesystem (e.g.
+    /// `"noatime,data=writeback,commit=60"`), or the tmpfs one with `root_image`. Generic ones
+    /// such as `ro` or `noatime` become mount flags; the rest are passed to the filesystem.
+    pub root_options: Option<String>,
+    /// Build the root filesystem from an archive instead of mounting an ext4 device: a tmpfs
+    /// (sized with `root_options`' `size=`) is filled from the archive and used as the root.
+    pub root_image: Option<RootImageConfig>,
+    /// Checks that the root filesystem is usable once it's mounted, to tell one that is
+    /// mounted but broken from one that can't be mounted at all.
+    pub root_check: Option<RootCheckConfig>,
+    #[serde(default)]
+    pub mounts: MountsConfig,
+    #[serde(default)]
+    pub devices: Vec<DeviceConfig>,
+    /// Interpreters to register with binfmt_misc, as the kernel takes them (e.g.
+    /// `":qemu-aarch64:M::\\x7fELF...:\\xff...:/usr/bin/qemu-aarch64-static:F"`), for running
+    /// other architectures' binaries.
+    #[serde(default)]
+    pub binfmt: Vec<String>,
+    #[serde(default)]
+    pub volumes: Vec<VolumeConfig>,
+    /// Paths (typically devices, e.g. `/dev/vdc`) that must exist before data volumes are
+    /// mounted and the rest of the guest is set up, for devices that show up late.
+    #[serde(default)]
+    pub wait_for: Vec<String>,
+    /// How long to wait for `wait_for` before carrying on without what's missing.
+    #[serde(default = "default_wait_for_timeout_ms")]
+    pub wait_for_timeout_ms: u64,
+    /// umask for init and everything it spawns. Defaults to 0022.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_mode",
+        serialize_with = "serialize_mode"
+    )]
+    pub umask: Option<u32>,
+    /// Level at which orphaned processes init reaps are logged, `off` to not log them at all.
+    /// They're counted in `/v1/sysinfo` either way.
+    #[serde(default = "default_reap_log_level")]
+    pub reap_log_level: LevelFilter,
+    /// When init isn't PID 1, mark it a child subreaper so orphans from the processes it
+    /// starts (daemons that double-fork, say) are reparented to init and reaped, rather than
+    /// to the real PID 1. PID 1 gets them regardless.
+    #[serde(default = "default_subreaper")]
+    pub subreaper: bool,
+    /// How long a child may stay in uninterruptible sleep, or a zombie, before it's reported
+    /// as stuck in the log, `/v1/events` and `/v1/health`. 0 turns the check off.
+    #[serde(default = "default_stuck_after_ms")]
+    pub stuck_after_ms: u64,
+    /// Threads running init's own work (the API, supervision, exec'd commands' I/O); one
+    /// per CPU by default. `INIT_WORKER_THREADS` overrides it.
+    pub worker_threads: Option<usize>,
+    #[serde(default)]
+    pub server: ServerConfig,
+    /// A manifest, usually on a data volume, read once the volumes are mounted (and again on
+    /// each reload). Its `ImageConfig` fields replace the ones set here, and its `files` and
+    /// `symlinks` are added after these, so its entries win when both write the same path.
+    /// The overrides here (`ExecOverride` and so on) still apply on top.
+    pub include: Option<String>,
+    /// Path inside the guest to watch for an updated run config. Changes are applied the same
+    /// way as `POST /v1/reload`.
+    pub watch_config: Option<String>,
+    /// What SIGHUP does: reload the run config from where it was read at boot, the same way
+    /// as an empty `POST /v1/reload` (the default), or nothing.
+    #[serde(default)]
+    pub sighup: SighupAction,
+    /// Where to announce that init is ready.
+    pub notify: Option<NotifyConfig>,
+    pub ready_file: Option<ReadyFileConfig>,
+    /// Commands run in order when init shuts down, before other processes are terminated.
+    #[serde(default)]
+    pub pre_stop: Vec<HookConfig>,
+    /// Processes started before the entrypoint and restarted according to their policy.
+    #[serde(default)]
+    pub sidecars: Vec<SidecarConfig>,
+}
+
+fn default_wait_for_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_base_env() -> HashMap<String, String> {
+    [
+        ("PATH", process::DEFAULT_PATH),
+        ("HOME", "/root"),
+        ("TERM", "linux"),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect()
+}
+
+/// The parts of the run config that can come from an `include`d manifest.
+#[derive(Deserialize, Debug)]
+struct Manifest {
+    #[serde(rename = "ImageConfig", default)]
+    image_config: ImageConfig,
+    #[serde(default)]
+    files: Vec<FileConfig>,
+    #[serde(default)]
+    symlinks: Vec<(String, String)>,
+}
+
+impl RunConfig {
+    pub fn load(path: &str) -> Result<RunConfig, Error> {
+        let raw = read_to_string(path).with_context(|| format!("reading {}", path))?;
+        RunConfig::from_str(&raw)
+    }
+
+    pub fn from_slice(raw: &[u8]) -> Result<RunConfig, Error> {
+        RunConfig::from_str(std::str::from_utf8(raw)?)
+    }
+
+    fn from_str(raw: &str) -> Result<RunConfig, Error> {
+        let config: RunConfig = serde_json::from_value(expand_env(raw)?)?;
+        config.check_file_collisions()?;
+        Ok(config)
+    }
+
+    /// Merges in the `include`d manifest, if there is one.
+    pub fn apply_include(&mut self) -> Result<(), Error> {
+        let Some(path) = &self.include else {
+            return Ok(());
+        };
+        if ops::dry_run() && !Path::new(path).exists() {
+            info!("dry-run: include manifest {}", path);
+            return Ok(());
+        }
+        let raw = read_to_string(path).with_context(|| format!("reading {}", path))?;
+        let manifest: Manifest = expand_env(&raw)
+            .and_then(|value| Ok(serde_json::from_value(value)?))
+            .with_context(|| format!("parsing {}", path))?;
+        info!(
+            "Including {}: {} files, {} symlinks",
+            path,
+            manifest.files.len(),
+            manifest.symlinks.len()
+        );
+
+        let image = manifest.image_config;
+        let ours = &mut self.image_config;
+        ours.entrypoint = image.entrypoint.or(ours.entrypoint.take());
+        ours.cmd = image.cmd.or(ours.cmd.take());
+        ours.env = image.env.or(ours.env.take());
+        ours.working_dir = image.working_dir.or(ours.working_dir.take());
+        ours.user = image.user.or(ours.user.take());
+        self.files.extend(manifest.files);
+        self.symlinks.extend(manifest.symlinks);
+        self.check_file_collisions()
+    }
+
+    fn check_file_collisions(&self) -> Result<(), Error> {
+        let mut first: HashMap<&str, usize> = HashMap::new();
+        let mut collisions = vec![];
+        for (i, file) in self.files.iter().enumerate() {
+            if file.append {
+                continue;
+            }
+            match first.entry(file.guest_path.as_str()) {
+                Entry::Occupied(earlier) => collisions.push(format!(
+                    "files[{}] and files[{}] both write {}",
+                    earlier.get(),
+                    i,
+                    file.guest_path
+                )),
+                Entry::Vacant(slot) => {
+                    slot.insert(i);
+                }
+            }
+        }
+        if collisions.is_empty() {
+            return Ok(());
+        }
+        match self.file_collisions {
+            CollisionPolicy::Warn => {
+                for collision in &collisions {
+                    warn!("{}; the last one wins", collision);
+                }
+                Ok(())
+            }
+            CollisionPolicy::Error => bail!("conflicting files: {}", collisions.join(", ")),
+        }
+    }
+
+    /// A copy that is safe to hand out over the control API: file contents, the TLS private
+    /// key, the auth token and environment variable values (which often carry secrets) are
+    /// removed. Variable names are kept.
+    pub fn redacted(&self) -> RunConfig {
+        let mut config = self.clone();
+        for var in config.image_config.env.iter_mut().flatten() {
+            if let Some((name, _)) = var.split_once('=') {
+                *var = format!("{}={}", name, REDACTED);
+            }
+        }
+        let maps = config
+            .extra_env
+            .iter_mut()
+            .chain([&mut config.base_env])
+            .chain(config.sidecars.iter_mut().map(|sidecar| &mut sidecar.env));
+        for map in maps {
+            for value in map.values_mut() {
+                *value = REDACTED.to_string();
+            }
+        }
+        for file in &mut config.files {
+            file.raw_value = REDACTED.to_string();
+        }
+        if let Some(tls) = &mut config.server.tls {
+            tls.key = REDACTED.to_string();
+        }
+        if let Some(token) = &mut config.server.auth_token {
+            *token = REDACTED.to_string();
+        }
+        config
+    }
+}
+
+/// Parses `raw` and expands `${NAME}` references in its string values from init's
+/// environment, so the host can inject individual values (e.g. via the kernel command line)
+/// without re-rendering the whole file. Keys and files' `raw_value`s are left as they are, so
+/// a file's contents can mention `${HOME}` freely. `$${` escapes a literal `${`. Referencing
+/// an unset variable is an error.
+fn expand_env(raw: &str) -> Result<serde_json::Value, Error> {
+    let mut value = serde_json::from_str(raw)?;
+    expand_value(&mut value)?;
+    Ok(value)
+}
+
+fn expand_value(value: &mut serde_json::Value) -> Result<(), Error> {
+    match value {
+        serde_json::Value::String(s) => *s = expand_str(s)?,
+        serde_json::Value::Array(values) => {
+            for value in values {
+                expand_value(value)?;
+            }
+        }
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                if key != "raw_value" {
+                    expand_value(value)?;
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn expand_str(raw: &str) -> Result<String, Error> {
+    let mut out = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(escaped) = rest.strip_prefix("$${") {
+            out.push_str("${");
+            rest = escaped;
+            continue;
+        }
+        let reference = rest
+            .strip_prefix("${")
+            .and_then(|r| r.find('}').map(|end| &r[..end]));
+        match reference {
+            Some(name)
+                if !name.is_empty()
+                    && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+            {
+                let value = env::var(name).map_err(|_| {
+                    anyhow!("run.json references unset environment variable {}", name)
+                })?;
+                out.push_str(&value);
+                rest = &rest[name.len() + 3..];
+            }
+            _ => {
+                out.push('$');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+pub fn show_config(state: SharedState) -> impl warp::Reply {
+    warp::reply::json(&state.config().redacted())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_values_without_reparsing_them() {
+        env::set_var(
+            "INIT_TEST_QUOTED",
+            "a \"quoted\" \\ value\n\", \"injected\": \"",
+        );
+        let config = RunConfig::from_str(r#"{"Hostname": "${INIT_TEST_QUOTED}"}"#).unwrap();
+        assert_eq!(
+            config.hostname.as_deref(),
+            Some("a \"quoted\" \\ value\n\", \"injected\": \"")
+        );
+    }
+
+    #[test]
+    fn redacts_environment_values() {
+        let config = RunConfig::from_str(
+            r#"{
+                "ImageConfig": {"Env": ["TOKEN=secret", "FLAG"]},
+                "ExtraEnv": {"DATABASE_URL": "postgres://secret"},
+                "base_env": {"PATH": "/bin"},
+                "sidecars": [{"name": "agent", "cmd": ["agent"], "env": {"KEY": "secret"}}]
+            }"#,
+        )
+        .unwrap()
+        .redacted();
+        assert_eq!(
+            config.image_config.env.unwrap(),
+            ["TOKEN=[redacted]", "FLAG"]
+        );
+        assert_eq!(config.extra_env.unwrap()["DATABASE_URL"], REDACTED);
+        assert_eq!(config.base_env["PATH"], REDACTED);
+        assert_eq!(config.sidecars[0].env["KEY"], REDACTED);
+    }
+
+    #[test]
+    fn parses_stop_signals() {
+        let config = RunConfig::from_str(
+            r#"{"sidecars": [
+                {"name": "a", "cmd": ["a"]},
+                {"name": "b", "cmd": ["b"], "stop_signal": "SIGINT"},
+                {"name": "c", "cmd": ["c"], "stop_signal": "quit"}
+            ]}"#,
+        )
+        .unwrap();
+        let signals: Vec<Signal> = config.sidecars.iter().map(|s| s.stop_signal).collect();
+        assert_eq!(signals, [Signal::SIGTERM, Signal::SIGINT, Signal::SIGQUIT]);
+        assert!(RunConfig::from_str(
+            r#"{"sidecars": [{"name": "a", "cmd": ["a"], "stop_signal": "SIGNOPE"}]}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn escaped_references_are_kept() {
+        assert_eq!(expand_str("$${HOME} costs $5").unwrap(), "${HOME} costs $5");
+    }
+
+    #[test]
+    fn unset_variables_are_an_error() {
+        let err = expand_str("${INIT_TEST_UNSET}").unwrap_err();
+        assert!(err.to_string().contains("INIT_TEST_UNSET"), "{}", err);
+    }
+
+    #[test]
+    fn file_contents_are_not_expanded() {
+        let config = RunConfig::from_str(
+            r#"{"files": [{"guest_path": "/${INIT_TEST_PATH}", "raw_value": "${INIT_TEST_UNSET}"}]}"#,
+        );
+        assert!(config.is_err(), "guest_path should still be expanded");
+        env::set_var("INIT_TEST_PATH", "run.sh");
+        let config = RunConfig::from_str(
+            r#"{"files": [{"guest_path": "/${INIT_TEST_PATH}", "raw_value": "${INIT_TEST_UNSET}"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(config.files[0].guest_path, "/run.sh");
+        assert_eq!(config.files[0].raw_value, "${INIT_TEST_UNSET}");
+    }
+}
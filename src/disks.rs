@@ -0,0 +1,52 @@
+//! `/v1/disks`: space and inodes left on each mounted filesystem.
+
+use super::mounts;
+use log::info;
+use nix::sys::statvfs::{statvfs, FsFlags};
+use serde::Serialize;
+
+#[derive(Serialize, Debug)]
+struct DiskUsage {
+    mount_point: String,
+    source: String,
+    fstype: String,
+    read_only: bool,
+    total_bytes: u64,
+    used_bytes: u64,
+    /// Space available to unprivileged processes, which excludes the blocks reserved for root.
+    available_bytes: u64,
+    total_inodes: u64,
+    free_inodes: u64,
+}
+
+/// Reports usage for every mounted filesystem that has a size. Pseudo filesystems (`/proc`,
+/// `/sys` and the like) report no blocks and are left out.
+pub fn list_disks() -> impl warp::Reply {
+    let mut disks = vec![];
+    for mount in mounts::mount_entries() {
+        let stats = match statvfs(mount.target.as_str()) {
+            Ok(stats) => stats,
+            Err(e) => {
+                info!("disks: skipping {}: {}", mount.target, e);
+                continue;
+            }
+        };
+        if stats.blocks() == 0 {
+            continue;
+        }
+        let block = stats.fragment_size() as u64;
+        disks.push(DiskUsage {
+            mount_point: mount.target,
+            source: mount.source,
+            fstype: mount.fstype,
+            read_only: stats.flags().contains(FsFlags::ST_RDONLY),
+            total_bytes: stats.blocks() as u64 * block,
+            used_bytes: (stats.blocks() - stats.blocks_free()) as u64 * block,
+            available_bytes: stats.blocks_available() as u64 * block,
+            total_inodes: stats.files() as u64,
+            free_inodes: stats.files_free() as u64,
+        });
+    }
+    info!("disks: {} filesystems", disks.len());
+    warp::reply::json(&disks)
+}
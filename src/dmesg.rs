@@ -0,0 +1,114 @@
+use super::{ApiReply, ErrorMessage};
+use anyhow::{anyhow, Error};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{self, Read};
+use std::os::unix::fs::OpenOptionsExt;
+use warp::http::StatusCode;
+
+#[derive(Deserialize, Debug)]
+pub struct DmesgQuery {
+    /// Only return records with a sequence number at or after this one. Pass the previous
+    /// response's `next_seq` to follow the ring buffer.
+    since: Option<u64>,
+    /// Only return the last `tail` records.
+    tail: Option<usize>,
+}
+
+#[derive(Serialize, Debug)]
+struct DmesgEntry {
+    seq: u64,
+    /// Microseconds since boot.
+    timestamp_us: u64,
+    facility: u8,
+    level: u8,
+    message: String,
+}
+
+#[derive(Serialize, Debug)]
+struct DmesgResponse {
+    entries: Vec<DmesgEntry>,
+    next_seq: u64,
+}
+
+/// Reads all records currently in the kernel ring buffer. Each read of /dev/kmsg returns one
+/// record; opening it non-blocking makes the read fail with EAGAIN once we're caught up.
+fn read_kmsg(since: u64) -> Result<DmesgResponse, Error> {
+    let mut kmsg = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open("/dev/kmsg")?;
+    let mut buf = vec![0; 8192];
+    let mut entries = vec![];
+    let mut next_seq = since;
+    loop {
+        let n = match kmsg.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            // The record we were about to read was overwritten; carry on with the next one.
+            Err(e) if e.raw_os_error() == Some(libc::EPIPE) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let entry = parse_record(&String::from_utf8_lossy(&buf[..n]))?;
+        next_seq = next_seq.max(entry.seq + 1);
+        if entry.seq >= since {
+            entries.push(entry);
+        }
+    }
+    Ok(DmesgResponse { entries, next_seq })
+}
+
+// A record looks like "<prio>,<seq>,<timestamp>,<flags>[,...];<message>\n", optionally
+// followed by " KEY=value" continuation lines, which are dropped.
+fn parse_record(record: &str) -> Result<DmesgEntry, Error> {
+    let (header, rest) = record
+        .split_once(';')
+        .ok_or_else(|| anyhow!("malformed kmsg record {:?}", record))?;
+    let mut fields = header.split(',');
+    let mut field = |name| -> Result<u64, Error> {
+        fields
+            .next()
+            .and_then(|f| f.parse().ok())
+            .ok_or_else(|| anyhow!("kmsg record has no valid {}: {:?}", name, header))
+    };
+    let prio = field("priority")?;
+    let seq = field("sequence number")?;
+    let timestamp_us = field("timestamp")?;
+    Ok(DmesgEntry {
+        seq,
+        timestamp_us,
+        facility: (prio >> 3) as u8,
+        level: (prio & 7) as u8,
+        message: rest.lines().next().unwrap_or_default().to_string(),
+    })
+}
+
+pub fn show_dmesg(query: DmesgQuery) -> impl warp::Reply {
+    let res = read_kmsg(query.since.unwrap_or(0)).map(|mut dmesg| {
+        if let Some(tail) = query.tail {
+            let skip = dmesg.entries.len().saturating_sub(tail);
+            dmesg.entries.drain(..skip);
+        }
+        dmesg
+    });
+
+    info!(
+        "dmesg: {:?}",
+        res.as_ref().map(|d| (d.entries.len(), d.next_seq))
+    );
+
+    match res {
+        Ok(d) => ApiReply::Ok(warp::reply::with_status(
+            warp::reply::json(&d),
+            StatusCode::OK,
+        )),
+        Err(e) => ApiReply::Err(warp::reply::with_status(
+            warp::reply::json(&ErrorMessage {
+                message: format!("{}", e),
+            }),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
@@ -0,0 +1,187 @@
+//! `/v1/file/edit`: small structured edits to a guest file (a config setting, a line), so
+//! callers don't have to quote them through `sed` in an exec.
+
+use super::ApiError;
+use log::info;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, Permissions};
+use std::io::{self, Write};
+use std::os::unix::fs::{chown, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize, Debug)]
+pub struct EditRequest {
+    path: String,
+    /// Applied in order; the file is only written if all of them apply.
+    edits: Vec<Edit>,
+    /// Start from an empty file if there is none. Otherwise a missing file is a 404.
+    #[serde(default)]
+    create: bool,
+}
+
+/// Patterns are regular expressions matched against each line, without its newline.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Edit {
+    /// e.g. `{"append_line": "nameserver 10.0.0.1"}`.
+    AppendLine(String),
+    DeleteLines {
+        matching: String,
+    },
+    /// Replaces every matching line with `with`.
+    ReplaceLines {
+        matching: String,
+        with: String,
+    },
+    /// Substitutes every match of `pattern` within lines; `with` may refer to groups as
+    /// `$1` or `${name}`.
+    Substitute {
+        pattern: String,
+        with: String,
+    },
+    /// Sets `key` to `value` in a `key=value` style file: every line setting `key` is
+    /// replaced, and the setting appended if there was none.
+    Set {
+        key: String,
+        value: String,
+        #[serde(default = "default_separator")]
+        separator: String,
+    },
+}
+
+impl Edit {
+    fn kind(&self) -> &'static str {
+        match self {
+            Edit::AppendLine(_) => "append_line",
+            Edit::DeleteLines { .. } => "delete_lines",
+            Edit::ReplaceLines { .. } => "replace_lines",
+            Edit::Substitute { .. } => "substitute",
+            Edit::Set { .. } => "set",
+        }
+    }
+}
+
+fn default_separator() -> String {
+    "=".to_string()
+}
+
+#[derive(Serialize, Debug)]
+struct EditResponse {
+    path: String,
+    /// False when the edits left the file as it was, in which case it wasn't rewritten.
+    changed: bool,
+    lines: usize,
+}
+
+fn regex(pattern: &str) -> Result<Regex, ApiError> {
+    Regex::new(pattern).map_err(|e| ApiError::BadRequest(format!("{:?}: {}", pattern, e)))
+}
+
+fn apply(lines: &mut Vec<String>, edit: &Edit) -> Result<(), ApiError> {
+    match edit {
+        Edit::AppendLine(line) => lines.push(line.clone()),
+        Edit::DeleteLines { matching } => {
+            let re = regex(matching)?;
+            lines.retain(|line| !re.is_match(line));
+        }
+        Edit::ReplaceLines { matching, with } => {
+            let re = regex(matching)?;
+            for line in lines.iter_mut().filter(|line| re.is_match(line)) {
+                *line = with.clone();
+            }
+        }
+        Edit::Substitute { pattern, with } => {
+            let re = regex(pattern)?;
+            for line in lines.iter_mut() {
+                *line = re.replace_all(line, with.as_str()).into_owned();
+            }
+        }
+        Edit::Set {
+            key,
+            value,
+            separator,
+        } => {
+            let re = regex(&format!(
+                r"^\s*{}\s*{}",
+                regex::escape(key),
+                regex::escape(separator)
+            ))?;
+            let setting = format!("{}{}{}", key, separator, value);
+            let mut found = false;
+            for line in lines.iter_mut().filter(|line| re.is_match(line)) {
+                *line = setting.clone();
+                found = true;
+            }
+            if !found {
+                lines.push(setting);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes `contents` next to `path` and renames it into place, so readers see the old file
+/// or the new one and never a partial write. The new file keeps the old one's mode and owner.
+fn replace_file(path: &Path, contents: &[u8], existing: Option<&fs::Metadata>) -> io::Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!(".{}.init-edit", name));
+    let written = (|| {
+        let mut file = File::create(&temp)?;
+        file.write_all(contents)?;
+        if let Some(meta) = existing {
+            file.set_permissions(Permissions::from_mode(meta.mode() & 0o7777))?;
+            chown(&temp, Some(meta.uid()), Some(meta.gid()))?;
+        }
+        file.sync_all()?;
+        fs::rename(&temp, path)
+    })();
+    if written.is_err() {
+        fs::remove_file(&temp).ok();
+    }
+    written
+}
+
+pub async fn handle_edit(req: EditRequest) -> Result<impl warp::Reply, warp::Rejection> {
+    // The new text is often a credential, so only what kind of edits were asked for is logged.
+    let kinds: Vec<&str> = req.edits.iter().map(Edit::kind).collect();
+    info!("file edit: {} {:?}", req.path, kinds);
+    let internal = |e: io::Error| ApiError::Internal(format!("{}: {}", req.path, e));
+
+    // Edit what a symlink points at rather than replacing the link.
+    let path = match fs::canonicalize(&req.path) {
+        Ok(path) => path,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && req.create => PathBuf::from(&req.path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(warp::reject::custom(ApiError::NotFound(format!(
+                "{} doesn't exist",
+                req.path
+            ))))
+        }
+        Err(e) => return Err(warp::reject::custom(internal(e))),
+    };
+    let existing = fs::metadata(&path).ok();
+    let original = match &existing {
+        Some(_) => fs::read_to_string(&path).map_err(internal)?,
+        None => String::new(),
+    };
+
+    let mut lines: Vec<String> = original.lines().map(String::from).collect();
+    for edit in &req.edits {
+        apply(&mut lines, edit)?;
+    }
+    let mut contents = lines.join("\n");
+    if !lines.is_empty() {
+        contents.push('\n');
+    }
+
+    let changed = contents != original || existing.is_none();
+    if changed {
+        replace_file(&path, contents.as_bytes(), existing.as_ref()).map_err(internal)?;
+    }
+    Ok(warp::reply::json(&EditResponse {
+        path: path.to_string_lossy().into_owned(),
+        changed,
+        lines: lines.len(),
+    }))
+}
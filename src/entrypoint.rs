@@ -0,0 +1,196 @@
+use super::config::RunConfig;
+use super::envfile;
+use super::events::{self, Event};
+use super::logs;
+use super::probe;
+use super::process::{self, ChildOptions};
+use super::reaper;
+use super::shutdown;
+use super::state::SharedState;
+use anyhow::{anyhow, Error};
+use log::{error, info};
+use nix::sys::signal::Signal;
+use nix::unistd::{execve, User};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStringExt;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+
+/// The argv to run: `ExecOverride` replaces everything, otherwise the image entrypoint
+/// followed by `CmdOverride` or the image command.
+pub fn argv(run_config: &RunConfig) -> Vec<String> {
+    if let Some(exec) = &run_config.exec_override {
+        return exec.clone();
+    }
+    let image = &run_config.image_config;
+    let mut argv = image.entrypoint.clone().unwrap_or_default();
+    argv.extend(
+        run_config
+            .cmd_override
+            .clone()
+            .or_else(|| image.cmd.clone())
+            .unwrap_or_default(),
+    );
+    argv
+}
+
+/// The workload's environment. Later sources win: the base environment, then the image's,
+/// then the env file, then `ExtraEnv`.
+pub fn environment(run_config: &RunConfig) -> Result<HashMap<String, String>, Error> {
+    let mut env = run_config.base_env.clone();
+    for var in run_config.image_config.env.iter().flatten() {
+        if let Some((key, value)) = var.split_once('=') {
+            env.insert(key.to_string(), value.to_string());
+        }
+    }
+    if let Some(path) = &run_config.entrypoint.env_file {
+        env.extend(envfile::load(path)?);
+    }
+    env.extend(run_config.extra_env.clone().unwrap_or_default());
+    Ok(env)
+}
+
+fn child_options(run_config: &RunConfig) -> Result<ChildOptions, Error> {
+    // The entrypoint gets a session of its own, as it would under any other init, so that
+    // job control and terminal signals reach it rather than init.
+    let mut opts = ChildOptions {
+        new_session: true,
+        ..Default::default()
+    };
+
+    if let Some(tty) = &run_config.entrypoint.tty {
+        opts.controlling_tty = Some(CString::new(tty.as_str())?);
+    }
+
+    if let Some(nice) = run_config.entrypoint.nice {
+        process::validate_nice(nice).map_err(Error::msg)?;
+        opts.nice = Some(nice);
+    }
+
+    if let Some(cpus) = &run_config.entrypoint.cpu_affinity {
+        opts.cpu_affinity = Some(process::cpu_affinity(cpus).map_err(Error::msg)?);
+    }
+
+    let user = run_config
+        .user_override
+        .as_ref()
+        .or(run_config.image_config.user.as_ref());
+    if let Some(name) = user {
+        let user = User::from_name(name)?.ok_or_else(|| anyhow!("unknown user {:?}", name))?;
+        opts.user = Some((user.uid, user.gid));
+    }
+
+    Ok(opts)
+}
+
+/// Starts the configured entrypoint, if there is one, along with its readiness probe. The
+/// returned task logs the entrypoint's exit and then completes.
+pub fn spawn(run_config: &RunConfig, state: SharedState) -> Result<Option<JoinHandle<()>>, Error> {
+    let argv = argv(run_config);
+    if argv.is_empty() {
+        info!("No entrypoint configured");
+        return Ok(None);
+    }
+    let image = &run_config.image_config;
+    let env = environment(run_config)?;
+
+    let program = process::resolve_program(&argv[0], env.get("PATH").map(String::as_str))
+        .map_err(|e| anyhow!("starting entrypoint: {}", e))?;
+    let mut cmd = Command::new(program);
+    cmd.arg0(&argv[0]).args(&argv[1..]).envs(&env);
+    if let Some(dir) = &image.working_dir {
+        cmd.current_dir(dir);
+    }
+
+    let opts = child_options(run_config)?;
+    process::apply(&mut cmd, &opts);
+    // Output is captured for `/v1/logs/entrypoint`, unless the entrypoint has a terminal.
+    if opts.controlling_tty.is_none() {
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+    }
+
+    info!("Starting entrypoint: {:?}", argv);
+    let (mut child, exited) = reaper::spawn(&mut cmd).map_err(|e| {
+        anyhow!(
+            "starting entrypoint: {}",
+            process::describe_spawn_error(&e, &opts)
+        )
+    })?;
+    if let Some(stdout) = child.stdout.take() {
+        logs::capture("entrypoint", stdout);
+    }
+    if let Some(stderr) = child.stderr.take() {
+        logs::capture("entrypoint", stderr);
+    }
+
+    state.process_started("entrypoint");
+    events::emit(Event::Started {
+        name: "entrypoint".to_string(),
+        pid: child.id(),
+    });
+    if let Some(pid) = child.id() {
+        shutdown::supervise(
+            "entrypoint",
+            pid,
+            run_config.entrypoint.shutdown_priority,
+            run_config
+                .entrypoint
+                .grace_period_ms
+                .map(Duration::from_millis),
+            Signal::SIGTERM,
+        );
+    }
+
+    if let Some(probe_config) = &run_config.entrypoint.readiness_probe {
+        let state = state.clone();
+        let set_ready = move |ready| state.set_entrypoint_ready(ready);
+        let name = "the entrypoint".to_string();
+        if let Err(e) = probe::start(probe_config.clone(), env, name, set_ready) {
+            error!("not running readiness probe: {:#}", e);
+        }
+    }
+
+    let handle = tokio::spawn(async move {
+        match exited.await {
+            Ok(exit) => {
+                info!(
+                    "Entrypoint exited (code {:?}, signal {:?})",
+                    exit.code, exit.signal
+                );
+                state.process_exited("entrypoint", &exit);
+                events::emit(Event::Exited {
+                    name: "entrypoint".to_string(),
+                    code: exit.code,
+                    signal: exit.signal,
+                });
+            }
+            Err(_) => error!("lost track of the entrypoint's exit status"),
+        }
+        drop(child);
+    });
+    Ok(Some(handle))
+}
+
+/// Replaces init with `argv` (the configured `init_cmd`), handing over PID 1 along with the
+/// workload's environment. Only returns if the exec fails.
+pub fn exec_init_cmd(run_config: &RunConfig, argv: &[String]) -> Result<Infallible, Error> {
+    let env = environment(run_config)?;
+    let name = argv.first().ok_or_else(|| anyhow!("init_cmd is empty"))?;
+    let program =
+        process::resolve_program(name, env.get("PATH").map(String::as_str)).map_err(Error::msg)?;
+    let program = CString::new(program.into_os_string().into_vec())?;
+    let args = argv
+        .iter()
+        .map(|a| CString::new(a.as_str()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let env = env
+        .iter()
+        .map(|(k, v)| CString::new(format!("{}={}", k, v)))
+        .collect::<Result<Vec<_>, _>>()?;
+    info!("Handing over to init_cmd: {:?}", argv);
+    Ok(execve(&program, &args, &env)?)
+}
@@ -0,0 +1,81 @@
+//! `.env`-style files: one `KEY=value` per line, with `#` comments, an optional `export `
+//! prefix, and single- or double-quoted values.
+
+use anyhow::{anyhow, Context, Error};
+use std::collections::HashMap;
+use std::fs::read_to_string;
+
+pub fn load(path: &str) -> Result<HashMap<String, String>, Error> {
+    let contents = read_to_string(path).with_context(|| format!("reading env file {}", path))?;
+    let mut vars = HashMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = parse_line(line).map_err(|e| anyhow!("{}:{}: {}", path, i + 1, e))?;
+        vars.insert(key, value);
+    }
+    Ok(vars)
+}
+
+fn parse_line(line: &str) -> Result<(String, String), String> {
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let (key, value) = line
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=value, got {:?}", line))?;
+    let key = key.trim();
+    let valid_key = key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid_key {
+        return Err(format!("invalid variable name {:?}", key));
+    }
+    Ok((key.to_string(), parse_value(value.trim())?))
+}
+
+fn parse_value(value: &str) -> Result<String, String> {
+    if let Some(rest) = value.strip_prefix('\'') {
+        // Single quotes are literal.
+        let end = rest
+            .find('\'')
+            .ok_or_else(|| "unterminated single quote".to_string())?;
+        check_trailing(&rest[end + 1..])?;
+        return Ok(rest[..end].to_string());
+    }
+    if let Some(rest) = value.strip_prefix('"') {
+        let mut out = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    check_trailing(&rest[i + 1..])?;
+                    return Ok(out);
+                }
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => out.push('\n'),
+                    Some((_, 't')) => out.push('\t'),
+                    Some((_, c)) => out.push(c),
+                    None => break,
+                },
+                c => out.push(c),
+            }
+        }
+        return Err("unterminated double quote".to_string());
+    }
+    // Unquoted: a " #" starts a comment.
+    let value = match value.find(" #") {
+        Some(i) => &value[..i],
+        None => value,
+    };
+    Ok(value.trim_end().to_string())
+}
+
+// Only a comment may follow a closing quote.
+fn check_trailing(rest: &str) -> Result<(), String> {
+    let rest = rest.trim_start();
+    if rest.is_empty() || rest.starts_with('#') {
+        Ok(())
+    } else {
+        Err(format!("unexpected text after closing quote: {:?}", rest))
+    }
+}
@@ -0,0 +1,191 @@
+use super::config::{EtcHost, EtcResolv};
+use super::ops::{self, write};
+use super::process;
+use anyhow::{anyhow, bail, Context, Error};
+use log::info;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const NSSWITCH_DEFAULTS: &[(&str, &str)] = &[
+    ("passwd", "files"),
+    ("group", "files"),
+    ("shadow", "files"),
+    ("hosts", "files dns"),
+    ("networks", "files"),
+    ("protocols", "files"),
+    ("services", "files"),
+];
+
+pub fn write_resolv_conf(resolv: Option<&EtcResolv>, hosts_only: bool) -> Result<(), Error> {
+    let contents = match resolv {
+        Some(r) if hosts_only && !r.nameservers.is_empty() => {
+            bail!("nameservers are configured but hosts_only_dns is set")
+        }
+        // With no nameserver line glibc tries one on localhost, which fails fast instead of
+        // timing out.
+        _ if hosts_only => "# hosts_only_dns: names resolve from /etc/hosts only\n".to_string(),
+        Some(r) => r
+            .nameservers
+            .iter()
+            .map(|ns| format!("nameserver {}\n", ns))
+            .collect(),
+        None => "nameserver 8.8.8.8\n".to_string(),
+    };
+    info!("Writing /etc/resolv.conf for DNS resolution...");
+    write("/etc/resolv.conf", contents)?;
+    Ok(())
+}
+
+pub fn write_hosts(hosts: Option<&[EtcHost]>) -> Result<(), Error> {
+    let mut contents = String::from("127.0.0.1 localhost\n");
+    for h in hosts.unwrap_or_default() {
+        if h.host == "localhost" && h.ip == "127.0.0.1" {
+            continue;
+        }
+        match &h.desc {
+            Some(desc) => contents.push_str(&format!("# {}\n{} {}\n", desc, h.ip, h.host)),
+            None => contents.push_str(&format!("{} {}\n", h.ip, h.host)),
+        }
+    }
+    info!("Writing /etc/hosts for local network resolution...");
+    write("/etc/hosts", contents)?;
+    Ok(())
+}
+
+/// Writes /etc/nsswitch.conf so lookups consult /etc/hosts before DNS (or, with
+/// `hosts_only`, instead of it). The entries in `overrides` replace or add to the defaults.
+/// An nsswitch.conf shipped by the image is kept unless overrides are configured or
+/// `hosts_only` is set.
+pub fn write_nsswitch_conf(
+    overrides: Option<&BTreeMap<String, String>>,
+    hosts_only: bool,
+) -> Result<(), Error> {
+    if overrides.is_none() && !hosts_only && Path::new("/etc/nsswitch.conf").exists() {
+        info!("Keeping the image's /etc/nsswitch.conf");
+        return Ok(());
+    }
+    let mut databases: BTreeMap<String, String> = NSSWITCH_DEFAULTS
+        .iter()
+        .map(|(db, sources)| (db.to_string(), sources.to_string()))
+        .collect();
+    if hosts_only {
+        databases.insert("hosts".to_string(), "files".to_string());
+    }
+    databases.extend(overrides.cloned().unwrap_or_default());
+
+    let contents: String = databases
+        .iter()
+        .map(|(db, sources)| format!("{}: {}\n", db, sources))
+        .collect();
+    info!("Writing /etc/nsswitch.conf...");
+    write("/etc/nsswitch.conf", contents)?;
+    Ok(())
+}
+
+/// Where `update-ca-certificates` picks up local certificates.
+const LOCAL_CA_DIR: &str = "/usr/local/share/ca-certificates";
+
+/// CA bundles distributions read, in the order they're looked for. Without
+/// `update-ca-certificates` the certificates are appended to the first that exists, or to
+/// the first one if none does.
+const CA_BUNDLES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/cert.pem",
+];
+
+/// Adds the PEM certificates in `certs` to the guest's trust store: through
+/// `update-ca-certificates` when the image has it, by appending to the CA bundle otherwise.
+/// Either way this can run at every boot of a persistent root: certificates written by an
+/// earlier boot are replaced, and ones the bundle already holds aren't appended again.
+pub async fn install_ca_certs(certs: &[String]) -> Result<(), Error> {
+    if certs.is_empty() {
+        return Ok(());
+    }
+    for (i, cert) in certs.iter().enumerate() {
+        if !cert.contains("-----BEGIN CERTIFICATE-----") {
+            bail!("ca_certs[{}] is not a PEM certificate", i);
+        }
+    }
+
+    if let Ok(update) = process::resolve_program("update-ca-certificates", None) {
+        ops::create_dir_all(Path::new(LOCAL_CA_DIR))?;
+        remove_local_ca_certs()?;
+        for (i, cert) in certs.iter().enumerate() {
+            write(
+                &format!("{}/init-{}.crt", LOCAL_CA_DIR, i),
+                with_newline(cert),
+            )?;
+        }
+        let update = update.to_string_lossy();
+        let (exit, output) = process::run_helper(&update, &[]).await?;
+        if exit.code != Some(0) {
+            return Err(anyhow!(
+                "update-ca-certificates failed (exit code {:?}, signal {:?}): {}",
+                exit.code,
+                exit.signal,
+                output.trim()
+            ));
+        }
+        info!(
+            "Added {} CA certificates with update-ca-certificates",
+            certs.len()
+        );
+        return Ok(());
+    }
+
+    let bundle = CA_BUNDLES
+        .iter()
+        .find(|b| Path::new(b).exists())
+        .unwrap_or(&CA_BUNDLES[0]);
+    if let Some(dir) = Path::new(bundle).parent() {
+        ops::create_dir_all(dir)?;
+    }
+    let existing = match fs::read_to_string(bundle) {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", bundle)),
+    };
+    let new: Vec<&String> = certs
+        .iter()
+        .filter(|cert| !existing.contains(cert.trim()))
+        .collect();
+    if new.is_empty() {
+        info!("{} already has the {} CA certificates", bundle, certs.len());
+        return Ok(());
+    }
+    let contents: String = new.iter().map(|cert| with_newline(cert)).collect();
+    ops::append(bundle, contents).with_context(|| format!("appending to {}", bundle))?;
+    info!(
+        "Added {} CA certificates to {} ({} were already there)",
+        new.len(),
+        bundle,
+        certs.len() - new.len()
+    );
+    Ok(())
+}
+
+/// Removes the `init-N.crt` files an earlier boot left for `update-ca-certificates`, which
+/// may have had more certificates than this one.
+fn remove_local_ca_certs() -> Result<(), Error> {
+    let entries = match fs::read_dir(LOCAL_CA_DIR) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", LOCAL_CA_DIR)),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name.starts_with("init-") && name.ends_with(".crt") {
+            let path = path.to_string_lossy();
+            ops::remove_file(&path).with_context(|| format!("removing {}", path))?;
+        }
+    }
+    Ok(())
+}
+
+fn with_newline(pem: &str) -> String {
+    format!("{}\n", pem.trim_end())
+}
@@ -0,0 +1,112 @@
+//! Init's lifecycle events (phases, supervised processes starting and exiting, signals), sent
+//! to whoever is following `/v1/events` as JSON Lines.
+
+use log::{info, warn};
+use serde::Serialize;
+use std::io;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use warp::http::header::{HeaderValue, CONTENT_TYPE};
+use warp::hyper::body::Bytes;
+use warp::hyper::Body;
+use warp::reply::Response;
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// Init moved on to another stage of boot or shutdown.
+    Phase {
+        phase: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        detail: Option<String>,
+    },
+    Started {
+        name: String,
+        pid: Option<u32>,
+    },
+    Exited {
+        name: String,
+        code: Option<i32>,
+        signal: Option<i32>,
+    },
+    Restarting {
+        name: String,
+        delay_ms: u128,
+    },
+    Signal {
+        signal: &'static str,
+    },
+    /// A child has been unkillable (`D`) or unreaped (`Z`) for longer than `stuck_after_ms`.
+    Stuck {
+        pid: i32,
+        command: String,
+        state: char,
+    },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    time_ms: u128,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+fn channel() -> &'static broadcast::Sender<Bytes> {
+    static EVENTS: OnceLock<broadcast::Sender<Bytes>> = OnceLock::new();
+    EVENTS.get_or_init(|| broadcast::channel(256).0)
+}
+
+/// Sends `event` to the current followers. Events aren't kept, so with none it goes nowhere.
+pub fn emit(event: Event) {
+    let tx = channel();
+    if tx.receiver_count() == 0 {
+        return;
+    }
+    let time_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    match serde_json::to_vec(&Record {
+        time_ms,
+        event: &event,
+    }) {
+        Ok(mut line) => {
+            line.push(b'\n');
+            tx.send(Bytes::from(line)).ok();
+        }
+        Err(e) => warn!("Failed to encode event {:?}: {}", event, e),
+    }
+}
+
+/// Shorthand for a phase event without detail.
+pub fn phase(phase: &'static str) {
+    emit(Event::Phase {
+        phase,
+        detail: None,
+    });
+}
+
+pub fn handle_events() -> Response {
+    info!("events");
+    let rx = channel().subscribe();
+    let stream = futures::stream::unfold(rx, |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(line) => return Some((Ok(line), rx)),
+                // A follower too slow to keep up misses events rather than holding up init.
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("events: follower missed {} events", n);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    let mut response = Response::new(Body::wrap_stream::<_, _, io::Error>(stream));
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/x-ndjson"),
+    );
+    response
+}
@@ -0,0 +1,455 @@
+use super::audit::ExecAudit;
+use super::envfile;
+use super::process::{self, ChildOptions, Rlimit, RlimitValue};
+use super::reaper::{self, Exit, Rusage};
+use super::state::SharedState;
+use super::ApiError;
+use log::info;
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{self, Read};
+use std::net::{SocketAddr, TcpListener};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::process::{Child, Command};
+use tokio::sync::oneshot;
+use warp::http::header::{HeaderMap, HeaderValue, CONTENT_TYPE, TRAILER};
+use warp::hyper::body::Bytes;
+use warp::hyper::Body;
+use warp::reply::{Reply, Response};
+
+#[derive(Deserialize, Debug)]
+pub struct ExecRequest {
+    cmd: Vec<String>,
+    /// Niceness (-20 to 19) to run the command at.
+    nice: Option<i32>,
+    /// CPUs (by index, from 0) to pin the command to.
+    cpu_affinity: Option<BTreeSet<usize>>,
+    /// Resource limits for the command alone, e.g. `{"cpu": 10, "as": {"soft": 1073741824,
+    /// "hard": 2147483648}}`.
+    #[serde(default)]
+    rlimits: BTreeMap<Rlimit, RlimitValue>,
+    /// Variables set on top of the configured base environment.
+    #[serde(default)]
+    env: HashMap<String, String>,
+    /// `.env`-style file in the guest to read variables from; `env` takes precedence over it.
+    env_file: Option<String>,
+    /// Overrides the format picked from the `Accept` header.
+    format: Option<ExecFormat>,
+    /// Run the command on a pseudo-terminal. Its stdout and stderr then arrive interleaved,
+    /// as they would on a screen, in `output`.
+    #[serde(default)]
+    tty: bool,
+    /// Kill the command, along with everything it started, if it runs longer than this.
+    timeout_ms: Option<u64>,
+    /// Listening sockets for init to open and pass to the command, for socket activation.
+    /// The n-th one (from zero) is the command's fd 3 + n and `LISTEN_FDS` holds how many
+    /// there are, as with systemd. `LISTEN_PID` isn't set, so libraries that require it
+    /// (sd_listen_fds) won't pick them up.
+    #[serde(default)]
+    sockets: Vec<ListenSocket>,
+}
+
+/// e.g. `{"tcp": "0.0.0.0:8080"}` or `{"unix": "/run/app.sock"}`.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum ListenSocket {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ListenSocket {
+    fn bind(&self) -> io::Result<OwnedFd> {
+        Ok(match self {
+            ListenSocket::Tcp(addr) => TcpListener::bind(addr)?.into(),
+            ListenSocket::Unix(path) => UnixListener::bind(path)?.into(),
+        })
+    }
+}
+
+/// How the result is returned: the JSON `ExecResponse`, or stdout as the raw body with the
+/// exit status in `X-Exit-Code`/`X-Signal` headers. `stream` sends stdout as it's written,
+/// for outputs too big to hold (a database dump, say), so the exit status can only follow
+/// in trailers; HTTP/1.1 clients don't see those, so there a failed command's response is
+/// cut off instead of ending cleanly.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ExecFormat {
+    Json,
+    Text,
+    Stream,
+}
+
+impl ExecFormat {
+    fn from_accept(accept: Option<&str>) -> ExecFormat {
+        match accept {
+            Some(accept) if accept.contains("application/octet-stream") => ExecFormat::Stream,
+            Some(accept)
+                if accept.contains("text/plain") && !accept.contains("application/json") =>
+            {
+                ExecFormat::Text
+            }
+            _ => ExecFormat::Json,
+        }
+    }
+}
+
+/// How much of a streamed command's output is read at a time.
+const STREAM_CHUNK: usize = 64 * 1024;
+
+#[derive(Serialize)]
+struct ExecResponse {
+    output: String,
+    exit_code: Option<i32>,
+    signal: Option<i32>,
+    rusage: Rusage,
+    timed_out: bool,
+}
+
+/// An exec'd command's process group (the command is its leader). Dropping it kills the
+/// whole group, so a command abandoned mid-request (its client went away, say) doesn't keep
+/// running.
+struct ProcessGroup(Pid);
+
+impl ProcessGroup {
+    fn kill(&self) {
+        killpg(self.0, Signal::SIGKILL).ok();
+    }
+
+    /// Leaves the group running.
+    fn release(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+pub async fn handle_exec(
+    state: SharedState,
+    accept: Option<String>,
+    req: ExecRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // The request's environment often carries secrets, so only the names are logged.
+    let mut env_names: Vec<&str> = req.env.keys().map(String::as_str).collect();
+    env_names.sort_unstable();
+    info!("Received request: {:?} (env: {:?})", req.cmd, env_names);
+    let format = req
+        .format
+        .unwrap_or_else(|| ExecFormat::from_accept(accept.as_deref()));
+
+    let server = state.config().server;
+    let mut audit = ExecAudit::start(server.audit_log, &req.cmd, server.auth_token.is_some());
+    let res = run(&state, format, req, &mut audit).await;
+    if let Err(e) = &res {
+        audit.failed(e.message());
+    }
+    Ok(res?)
+}
+
+async fn run(
+    state: &SharedState,
+    format: ExecFormat,
+    req: ExecRequest,
+    audit: &mut ExecAudit,
+) -> Result<Response, ApiError> {
+    if !state.is_ready() {
+        return Err(ApiError::Unavailable(
+            "init is still setting up the guest".to_string(),
+        ));
+    }
+
+    if req.cmd.is_empty() {
+        return Err(ApiError::BadRequest("No command provided".to_string()));
+    }
+    if req.tty && format == ExecFormat::Stream {
+        return Err(ApiError::BadRequest(
+            "the stream format can't be used with tty".to_string(),
+        ));
+    }
+
+    let mut env = state.config().base_env;
+    if let Some(path) = &req.env_file {
+        env.extend(envfile::load(path).map_err(|e| ApiError::BadRequest(format!("{:#}", e)))?);
+    }
+    env.extend(req.env.clone());
+    // Init's copies are closed as soon as the command has started with its own.
+    let sockets = req
+        .sockets
+        .iter()
+        .map(|socket| {
+            socket
+                .bind()
+                .map_err(|e| ApiError::BadRequest(format!("binding {:?}: {}", socket, e)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if !sockets.is_empty() {
+        env.insert("LISTEN_FDS".to_string(), sockets.len().to_string());
+    }
+    let path = env.get("PATH");
+    let program = process::resolve_program(&req.cmd[0], path.map(String::as_str))
+        .map_err(ApiError::BadRequest)?;
+    let mut cmd = Command::new(program);
+    cmd.arg0(&req.cmd[0]);
+    if req.cmd.len() > 1 {
+        cmd.args(&req.cmd[1..]);
+    }
+    cmd.envs(&env);
+
+    let mut opts = ChildOptions {
+        new_process_group: true,
+        pass_fds: sockets.iter().map(AsRawFd::as_raw_fd).collect(),
+        ..Default::default()
+    };
+    if let Some(nice) = req.nice {
+        process::validate_nice(nice).map_err(ApiError::BadRequest)?;
+        opts.nice = Some(nice);
+    }
+    if let Some(cpus) = &req.cpu_affinity {
+        opts.cpu_affinity = Some(process::cpu_affinity(cpus).map_err(ApiError::BadRequest)?);
+    }
+    opts.rlimits = process::rlimits(&req.rlimits).map_err(ApiError::BadRequest)?;
+    let pty = if req.tty {
+        let pty = process::open_pty()
+            .map_err(|e| ApiError::Internal(format!("Failed to allocate a pty: {:#}", e)))?;
+        opts.controlling_tty = Some(pty.path.clone());
+        Some(pty)
+    } else {
+        None
+    };
+    process::apply(&mut cmd, &opts);
+
+    // With a pty the child's stdio is replaced by the terminal in its pre_exec hook.
+    if pty.is_some() {
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+    } else {
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+    }
+    let internal = |e: std::io::Error| {
+        ApiError::Internal(format!(
+            "Failed to execute command: {}",
+            process::describe_spawn_error(&e, &opts)
+        ))
+    };
+    let (mut child, exited) = reaper::spawn(&mut cmd).map_err(internal)?;
+    drop(sockets);
+    let group = child
+        .id()
+        .map(|pid| ProcessGroup(Pid::from_raw(pid as i32)));
+    if format == ExecFormat::Stream {
+        let timeout = req.timeout_ms.map(Duration::from_millis);
+        return Ok(stream(child, exited, group, timeout, audit.take()));
+    }
+
+    let pipes = (child.stdout.take(), child.stderr.take());
+    let collect = async move {
+        match pty {
+            Some(pty) => {
+                let mut master = pty.master;
+                let reader = tokio::task::spawn_blocking(move || {
+                    let mut output = vec![];
+                    match master.read_to_end(&mut output) {
+                        // EIO is how the master reports that the terminal side was closed.
+                        Err(e) if e.raw_os_error() != Some(libc::EIO) => Err(e),
+                        _ => Ok(output),
+                    }
+                });
+                let exit = exited.await;
+                drop(pty.terminal);
+                let stdout = reader
+                    .await
+                    .map_err(|e| ApiError::Internal(e.to_string()))?
+                    .map_err(internal)?;
+                Ok((exit, stdout))
+            }
+            None => {
+                let mut stdout = vec![];
+                let mut stderr = vec![];
+                let (mut child_out, mut child_err) = (pipes.0.unwrap(), pipes.1.unwrap());
+                let (out_res, err_res) = tokio::join!(
+                    child_out.read_to_end(&mut stdout),
+                    child_err.read_to_end(&mut stderr)
+                );
+                out_res.map_err(internal)?;
+                err_res.map_err(internal)?;
+                Ok((exited.await, stdout))
+            }
+        }
+    };
+    tokio::pin!(collect);
+    let mut timed_out = false;
+    let res: Result<_, ApiError> = match req.timeout_ms.map(Duration::from_millis) {
+        Some(timeout) => match tokio::time::timeout(timeout, &mut collect).await {
+            Ok(res) => res,
+            Err(_) => {
+                info!("Command timed out after {:?}, killing it", timeout);
+                timed_out = true;
+                if let Some(group) = &group {
+                    group.kill();
+                }
+                collect.await
+            }
+        },
+        None => collect.await,
+    };
+    let (exit, stdout) = res?;
+    let exit = exit.map_err(|_| ApiError::Internal("child exit status was lost".to_string()))?;
+    audit.exited(&exit, timed_out);
+    drop(child);
+    // The command finished; anything it left running in the background is its own business.
+    if let Some(group) = group {
+        group.release();
+    }
+
+    if format == ExecFormat::Text {
+        let mut response = Response::new(Body::from(stdout));
+        let headers = response.headers_mut();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; charset=utf-8"),
+        );
+        if let Some(code) = exit.code {
+            headers.insert("x-exit-code", HeaderValue::from(code));
+        }
+        if let Some(signal) = exit.signal {
+            headers.insert("x-signal", HeaderValue::from(signal));
+        }
+        if timed_out {
+            headers.insert("x-timed-out", HeaderValue::from_static("true"));
+        }
+        return Ok(response);
+    }
+
+    let response = ExecResponse {
+        output: String::from_utf8_lossy(&stdout).to_string(),
+        exit_code: exit.code,
+        signal: exit.signal,
+        rusage: exit.rusage,
+        timed_out,
+    };
+    Ok(warp::reply::json(&response).into_response())
+}
+
+/// Responds with the command's stdout as it's written, in a task that outlives the handler and
+/// finishes the body (or cuts it off) once the command exits. Its stderr is only logged.
+fn stream(
+    mut child: Child,
+    exited: oneshot::Receiver<Exit>,
+    group: Option<ProcessGroup>,
+    timeout: Option<Duration>,
+    mut audit: ExecAudit,
+) -> Response {
+    let (mut tx, body) = Body::channel();
+    let (mut stdout, mut stderr) = (child.stdout.take().unwrap(), child.stderr.take().unwrap());
+    tokio::spawn(async move {
+        let mut timed_out = false;
+        let (copied, errors, exit) = {
+            let copy = async {
+                let mut buf = vec![0; STREAM_CHUNK];
+                loop {
+                    let n = stdout.read(&mut buf).await?;
+                    if n == 0 {
+                        return Ok(());
+                    }
+                    if let Err(e) = tx.send_data(Bytes::copy_from_slice(&buf[..n])).await {
+                        // The client went away; nobody is left to read the rest.
+                        if let Some(group) = &group {
+                            group.kill();
+                        }
+                        return Err(io::Error::new(io::ErrorKind::BrokenPipe, e));
+                    }
+                }
+            };
+            let drain = async {
+                let mut errors = vec![];
+                stderr.read_to_end(&mut errors).await.ok();
+                errors
+            };
+            let run = async {
+                let (copied, errors) = tokio::join!(copy, drain);
+                (copied, errors, exited.await)
+            };
+            tokio::pin!(run);
+            match timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, &mut run).await {
+                    Ok(res) => res,
+                    Err(_) => {
+                        info!("Command timed out after {:?}, killing it", timeout);
+                        timed_out = true;
+                        if let Some(group) = &group {
+                            group.kill();
+                        }
+                        run.await
+                    }
+                },
+                None => run.await,
+            }
+        };
+        drop(child);
+        if let Some(group) = group {
+            group.release();
+        }
+
+        let Ok(exit) = exit else {
+            audit.failed("child exit status was lost");
+            tx.abort();
+            return;
+        };
+        audit.exited(&exit, timed_out);
+        if let Err(e) = copied {
+            info!("exec stream: stopped sending output: {}", e);
+            audit.failed(&format!("sending output: {}", e));
+            tx.abort();
+            return;
+        }
+        if exit.code != Some(0) || timed_out {
+            info!(
+                "exec stream: command failed (exit code {:?}, signal {:?}): {}",
+                exit.code,
+                exit.signal,
+                String::from_utf8_lossy(&errors).trim()
+            );
+        }
+
+        let mut trailers = HeaderMap::new();
+        if let Some(code) = exit.code {
+            trailers.insert("x-exit-code", HeaderValue::from(code));
+        }
+        if let Some(signal) = exit.signal {
+            trailers.insert("x-signal", HeaderValue::from(signal));
+        }
+        if timed_out {
+            trailers.insert("x-timed-out", HeaderValue::from_static("true"));
+        }
+        tx.send_trailers(trailers).await.ok();
+        // Cutting the body off is what tells an HTTP/1.1 client the output is incomplete.
+        if exit.code != Some(0) || timed_out {
+            tx.abort();
+        }
+    });
+
+    let mut response = Response::new(body);
+    let headers = response.headers_mut();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    headers.insert(
+        TRAILER,
+        HeaderValue::from_static("x-exit-code, x-signal, x-timed-out"),
+    );
+    response
+}
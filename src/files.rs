@@ -0,0 +1,354 @@
+use super::config::{FileConfig, FileEncoding};
+use super::ops;
+use anyhow::{bail, Context, Error};
+use base64::alphabet;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use base64::Engine as _;
+use log::{error, info, warn};
+use serde::Serialize;
+use std::fs::{self, read_link, Permissions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+const PADDING_OPTIONAL: GeneralPurposeConfig =
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent);
+const BASE64: GeneralPurpose = GeneralPurpose::new(&alphabet::STANDARD, PADDING_OPTIONAL);
+const BASE64_URL: GeneralPurpose = GeneralPurpose::new(&alphabet::URL_SAFE, PADDING_OPTIONAL);
+
+// How much of an undecodable value to show when reporting it.
+const PREVIEW_LEN: usize = 20;
+
+/// How much `write_files` wrote: files preserved or skipped after an error don't count.
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct WriteSummary {
+    pub files_written: usize,
+    pub bytes_written: usize,
+}
+
+/// Writes the configured files, under `root` if one is given. A file that fails stops the
+/// rest only if it is marked `required`, unless `transactional` is set, in which case any
+/// failure leaves every file as it was.
+pub fn write_files(
+    files: &[FileConfig],
+    root: Option<&str>,
+    transactional: bool,
+) -> Result<WriteSummary, Error> {
+    // A dry run only logs the writes, which the per-file path does just as well.
+    if transactional && !ops::dry_run() {
+        return write_files_transaction(files, root);
+    }
+    let mut summary = WriteSummary::default();
+    for file_config in files {
+        match write_file(file_config, root) {
+            Ok(Some(bytes)) => {
+                summary.files_written += 1;
+                summary.bytes_written += bytes;
+            }
+            Ok(None) => {}
+            Err(e) if file_config.required => return Err(e),
+            Err(e) => error!("{:#}, skipping it", e),
+        }
+    }
+    info!(
+        "Wrote {} of {} files ({} bytes)",
+        summary.files_written,
+        files.len(),
+        summary.bytes_written
+    );
+    Ok(summary)
+}
+
+/// Where a file's `guest_path` is written: joined onto `root` when there is one, with an
+/// absolute path taken as relative to it. `..` is refused either way, and with a root so is
+/// a path that leads out of it through a symlink.
+fn resolve(guest_path: &str, root: Option<&str>) -> Result<String, Error> {
+    let path = Path::new(guest_path);
+    if path.components().any(|c| c == Component::ParentDir) {
+        bail!("file {}: guest_path can't contain ..", guest_path);
+    }
+    let Some(root) = root else {
+        return Ok(guest_path.to_string());
+    };
+    let relative: PathBuf = path
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect();
+    let full = Path::new(root).join(relative);
+    // Only the part of the path that already exists can hold a symlink.
+    if let Ok(real_root) = Path::new(root).canonicalize() {
+        let existing = full.ancestors().find_map(|p| p.canonicalize().ok());
+        if existing.is_some_and(|existing| !existing.starts_with(&real_root)) {
+            bail!("file {}: leads outside files_root {}", guest_path, root);
+        }
+    }
+    Ok(full.to_string_lossy().into_owned())
+}
+
+fn decode(file_config: &FileConfig, path: &str) -> Result<Vec<u8>, Error> {
+    let (engine, name) = match file_config.encoding {
+        FileEncoding::Base64 => (&BASE64, "base64"),
+        FileEncoding::Base64Url => (&BASE64_URL, "url-safe base64"),
+    };
+    engine.decode(&file_config.raw_value).with_context(|| {
+        format!(
+            "file {}: raw_value {} is not valid {}",
+            path,
+            preview(&file_config.raw_value),
+            name
+        )
+    })
+}
+
+/// Returns how many bytes were written, or None if an existing file was preserved.
+fn write_file(file_config: &FileConfig, root: Option<&str>) -> Result<Option<usize>, Error> {
+    let path = &resolve(&file_config.guest_path, root)?;
+    if !file_config.overwrite && Path::new(path).symlink_metadata().is_ok() {
+        info!("File {} already exists, preserving it", path);
+        return Ok(None);
+    }
+    let decoded_data = decode(file_config, path)?;
+    if file_config.append && already_appended(path, &decoded_data) {
+        info!(
+            "File {} already ends with its contents, not appending again",
+            path
+        );
+        return Ok(None);
+    }
+    if file_config.append {
+        ops::append(path, &decoded_data).with_context(|| format!("appending to {}", path))?;
+    } else {
+        ops::write(path, &decoded_data).with_context(|| format!("writing {}", path))?;
+    }
+    if let Some(mode) = file_config.mode {
+        ops::set_mode(path, mode).with_context(|| format!("setting the mode of {}", path))?;
+    }
+    info!("Saved file: {}", path);
+    Ok(Some(decoded_data.len()))
+}
+
+/// Whether the file at `path` already ends with `data`, as it does once an append has been
+/// applied. Appends are skipped then, so writing the files again (on reload, or at the next
+/// boot on a persistent volume) doesn't repeat them.
+fn already_appended(path: &str, data: &[u8]) -> bool {
+    let Ok(mut file) = fs::File::open(path) else {
+        return false;
+    };
+    let len = data.len() as u64;
+    match file.metadata() {
+        Ok(metadata) if metadata.len() >= len => {}
+        _ => return false,
+    }
+    let mut tail = vec![0; data.len()];
+    file.seek(SeekFrom::End(-(len as i64))).is_ok()
+        && file.read_exact(&mut tail).is_ok()
+        && tail == data
+}
+
+/// A file's final contents, ready to be moved into place.
+struct Staged {
+    path: String,
+    contents: Vec<u8>,
+    mode: Option<u32>,
+    /// The new contents, under a temporary name next to `path`.
+    temp: String,
+    /// A link to the original, if there was one, kept until every file is in place.
+    backup: Option<String>,
+}
+
+fn sibling(path: &str, suffix: &str) -> String {
+    let path = Path::new(path);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.{}", name, suffix))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// `write_files` for `transactional_files`. Each path's final contents are worked out first
+/// (appends included), then written to temporary files, then renamed over the originals.
+/// The originals are kept (as hard links) until the last rename succeeds, so a failure at
+/// any point puts every file back.
+fn write_files_transaction(
+    files: &[FileConfig],
+    root: Option<&str>,
+) -> Result<WriteSummary, Error> {
+    let mut staged: Vec<Staged> = vec![];
+    for file_config in files {
+        let path = resolve(&file_config.guest_path, root)?;
+        // Renaming over a symlink would replace the link, where writing goes through it.
+        let path = fs::canonicalize(&path)
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or(path);
+        let data = decode(file_config, &path)?;
+        if let Some(earlier) = staged.iter_mut().find(|s| s.path == path) {
+            if !file_config.append {
+                earlier.contents.clear();
+            }
+            earlier.contents.extend(data);
+            earlier.mode = file_config.mode.or(earlier.mode);
+            continue;
+        }
+        if !file_config.overwrite && Path::new(&path).symlink_metadata().is_ok() {
+            info!("File {} already exists, preserving it", path);
+            continue;
+        }
+        let mut contents = vec![];
+        if file_config.append {
+            match fs::read(&path) {
+                Ok(existing) if existing.ends_with(&data) => {
+                    info!(
+                        "File {} already ends with its contents, not appending again",
+                        path
+                    );
+                    continue;
+                }
+                Ok(existing) => contents = existing,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).with_context(|| format!("reading {}", path)),
+            }
+        }
+        contents.extend(data);
+        staged.push(Staged {
+            temp: sibling(&path, "init-new"),
+            path,
+            contents,
+            mode: file_config.mode,
+            backup: None,
+        });
+    }
+
+    let mut committed = 0;
+    let res = commit(&mut staged, &mut committed);
+    if res.is_err() {
+        for file in &staged[..committed] {
+            match &file.backup {
+                Some(backup) => fs::rename(backup, &file.path).ok(),
+                None => fs::remove_file(&file.path).ok(),
+            };
+        }
+        error!(
+            "Writing files failed, leaving all {} as they were",
+            files.len()
+        );
+    }
+    for file in &staged {
+        fs::remove_file(&file.temp).ok();
+        if let Some(backup) = &file.backup {
+            fs::remove_file(backup).ok();
+        }
+    }
+    res?;
+
+    let summary = WriteSummary {
+        files_written: staged.len(),
+        bytes_written: staged.iter().map(|s| s.contents.len()).sum(),
+    };
+    info!(
+        "Wrote {} files ({} bytes) as one transaction",
+        summary.files_written, summary.bytes_written
+    );
+    Ok(summary)
+}
+
+/// Writes every staged file under its temporary name, then renames each into place,
+/// counting in `committed` how many have been.
+fn commit(staged: &mut [Staged], committed: &mut usize) -> Result<(), Error> {
+    for file in staged.iter() {
+        fs::write(&file.temp, &file.contents).with_context(|| format!("writing {}", file.temp))?;
+        // Rewriting a file with no mode given keeps its mode, as writing in place would.
+        let mode = file.mode.or_else(|| {
+            fs::metadata(&file.path)
+                .ok()
+                .map(|m| m.permissions().mode() & 0o7777)
+        });
+        if let Some(mode) = mode {
+            fs::set_permissions(&file.temp, Permissions::from_mode(mode))
+                .with_context(|| format!("setting the mode of {}", file.temp))?;
+        }
+    }
+    for file in staged.iter_mut() {
+        if Path::new(&file.path).exists() {
+            let backup = sibling(&file.path, "init-old");
+            fs::remove_file(&backup).ok();
+            fs::hard_link(&file.path, &backup)
+                .with_context(|| format!("keeping a copy of {}", file.path))?;
+            file.backup = Some(backup);
+        }
+        fs::rename(&file.temp, &file.path)
+            .with_context(|| format!("moving {} into place", file.path))?;
+        *committed += 1;
+        info!("Saved file: {}", file.path);
+    }
+    Ok(())
+}
+
+fn preview(value: &str) -> String {
+    match value.char_indices().nth(PREVIEW_LEN) {
+        Some((i, _)) => format!("{:?}... ({} bytes)", &value[..i], value.len()),
+        None => format!("{:?}", value),
+    }
+}
+
+/// Creates each `(target, link)` symlink, along with the link's parent directories. Links
+/// that already exist are left alone, so this can be reapplied.
+pub fn create_symlinks(links: &[(String, String)]) -> Result<(), Error> {
+    for (target, link) in links {
+        match read_link(link) {
+            Ok(existing) if existing == Path::new(target) => {
+                warn!("Symlink {} -> {} already exists, skipping", link, target);
+                continue;
+            }
+            Ok(existing) => {
+                warn!(
+                    "{} already links to {}, not pointing it at {}",
+                    link,
+                    existing.display(),
+                    target
+                );
+                continue;
+            }
+            Err(_) if Path::new(link).symlink_metadata().is_ok() => {
+                warn!("{} already exists and isn't a symlink, skipping", link);
+                continue;
+            }
+            Err(_) => {}
+        }
+        if let Some(parent) = Path::new(link).parent() {
+            ops::create_dir_all(parent)
+                .with_context(|| format!("creating {}", parent.display()))?;
+        }
+        ops::symlink(target, link).with_context(|| format!("linking {} -> {}", link, target))?;
+        info!("Created symlink: {} -> {}", link, target);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn appends_are_not_repeated() {
+        let path = std::env::temp_dir().join(format!("init-append-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, "UUID=1 / ext4 defaults 0 1\n").unwrap();
+        let file = FileConfig {
+            guest_path: path.to_string(),
+            raw_value: BASE64.encode("tmpfs /tmp tmpfs defaults 0 0\n"),
+            encoding: FileEncoding::Base64,
+            mode: None,
+            append: true,
+            overwrite: true,
+            required: true,
+            reloadable: false,
+        };
+        assert_eq!(write_file(&file, None).unwrap(), Some(30));
+        assert_eq!(write_file(&file, None).unwrap(), None);
+        let contents = fs::read_to_string(path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(
+            contents,
+            "UUID=1 / ext4 defaults 0 1\ntmpfs /tmp tmpfs defaults 0 0\n"
+        );
+    }
+}
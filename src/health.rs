@@ -0,0 +1,70 @@
+use super::mounts;
+use super::reaper::{self, StuckProcess};
+use super::state::{SharedState, SidecarStatus};
+use nix::sys::utsname::uname;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use warp::http::StatusCode;
+
+#[derive(Serialize, Debug)]
+struct Health {
+    /// Init has finished setting up the guest.
+    setup_complete: bool,
+    /// Whether the entrypoint's readiness probe has passed; null without a probe.
+    entrypoint_ready: Option<bool>,
+    /// True only if every sidecar is healthy too.
+    ready: bool,
+    sidecars: BTreeMap<String, SidecarStatus>,
+    /// Files written from the run config, the last time it was applied.
+    files_written: usize,
+    bytes_written: usize,
+    init_version: &'static str,
+    /// The kernel release, as `uname -r` prints it.
+    kernel_version: Option<String>,
+    kernel_cmdline: Option<String>,
+    /// Why init is in recovery mode, running nothing but the control API; null normally.
+    recovery: Option<String>,
+    /// Children stuck unkillable or unreaped; they don't affect `ready`.
+    stuck_processes: Vec<StuckProcess>,
+    /// `/proc` or `/sys`, if either failed to mount. Endpoints that need them are
+    /// unavailable.
+    missing_mounts: Vec<&'static str>,
+}
+
+/// Reports readiness for orchestrators: 200 once setup is done and the entrypoint's and
+/// sidecars' readiness probes (if any) have passed, 503 until then.
+pub fn show_health(state: SharedState) -> impl warp::Reply {
+    let setup_complete = state.is_ready();
+    let entrypoint_ready = state.entrypoint_ready();
+    let files = state.files_written();
+    let sidecars = state.sidecars();
+    let recovery = state.recovery();
+    let health = Health {
+        setup_complete,
+        entrypoint_ready,
+        ready: setup_complete
+            && recovery.is_none()
+            && entrypoint_ready.unwrap_or(true)
+            && sidecars.values().all(SidecarStatus::is_healthy),
+        sidecars,
+        files_written: files.files_written,
+        bytes_written: files.bytes_written,
+        init_version: env!("CARGO_PKG_VERSION"),
+        kernel_version: uname()
+            .ok()
+            .map(|u| u.release().to_string_lossy().into_owned()),
+        kernel_cmdline: read_to_string("/proc/cmdline")
+            .ok()
+            .map(|c| c.trim_end().to_string()),
+        recovery,
+        stuck_processes: reaper::stuck_processes(),
+        missing_mounts: mounts::missing_mounts(),
+    };
+    let status = if health.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    warp::reply::with_status(warp::reply::json(&health), status)
+}
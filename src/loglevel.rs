@@ -0,0 +1,60 @@
+//! Changing how verbose init's logging is while it runs.
+
+use super::{ApiReply, ErrorMessage};
+use log::{info, LevelFilter};
+use serde::{Deserialize, Serialize};
+use warp::http::StatusCode;
+
+#[derive(Deserialize, Debug)]
+pub struct LogLevelRequest {
+    /// `off`, `error`, `warn`, `info`, `debug` or `trace`.
+    level: String,
+}
+
+#[derive(Serialize, Debug)]
+struct LogLevelResponse {
+    level: String,
+    previous: String,
+}
+
+/// Starts the logger. It lets every record through and the level is held by `log`'s global
+/// maximum instead, which unlike env_logger's own filter can be changed afterwards.
+pub fn init(level: LevelFilter) {
+    env_logger::builder()
+        .filter_level(LevelFilter::Trace)
+        .init();
+    log::set_max_level(level);
+}
+
+pub fn show_log_level() -> impl warp::Reply {
+    warp::reply::json(&serde_json::json!({ "level": log::max_level().to_string().to_lowercase() }))
+}
+
+pub fn handle_log_level(req: LogLevelRequest) -> impl warp::Reply {
+    let res = req.level.parse::<LevelFilter>();
+    info!("loglevel: {:?}", res);
+    match res {
+        Ok(level) => {
+            let previous = log::max_level();
+            log::set_max_level(level);
+            // Logged at warn so the change shows up whichever way it went.
+            log::warn!("Log level changed from {} to {}", previous, level);
+            ApiReply::Ok(warp::reply::with_status(
+                warp::reply::json(&LogLevelResponse {
+                    level: level.to_string().to_lowercase(),
+                    previous: previous.to_string().to_lowercase(),
+                }),
+                StatusCode::OK,
+            ))
+        }
+        Err(_) => ApiReply::Err(warp::reply::with_status(
+            warp::reply::json(&ErrorMessage {
+                message: format!(
+                    "unknown log level {:?}, expected off, error, warn, info, debug or trace",
+                    req.level
+                ),
+            }),
+            StatusCode::BAD_REQUEST,
+        )),
+    }
+}
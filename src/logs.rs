@@ -0,0 +1,155 @@
+//! Output of supervised processes, kept in a bounded buffer per process so the host can read
+//! it over the control API. Everything captured is still copied to init's own stdout.
+
+use super::ApiError;
+use log::{info, warn};
+use serde::Deserialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::broadcast;
+use warp::http::header::{HeaderValue, CONTENT_TYPE};
+use warp::hyper::body::Bytes;
+use warp::hyper::Body;
+use warp::reply::Response;
+
+/// Bytes of output kept per process. The oldest lines are dropped to make room.
+const BUFFER_SIZE: usize = 256 * 1024;
+/// Longer lines are split, so a process that never writes a newline can't grow one line
+/// without bound.
+const MAX_LINE: usize = 16 * 1024;
+const DEFAULT_LINES: usize = 100;
+
+struct ProcessLog {
+    lines: VecDeque<Bytes>,
+    bytes: usize,
+    // For followers; sending fails harmlessly when there are none.
+    tx: broadcast::Sender<Bytes>,
+}
+
+impl ProcessLog {
+    fn push(&mut self, line: Bytes) {
+        self.bytes += line.len();
+        self.lines.push_back(line.clone());
+        while self.bytes > BUFFER_SIZE {
+            match self.lines.pop_front() {
+                Some(old) => self.bytes -= old.len(),
+                None => break,
+            }
+        }
+        self.tx.send(line).ok();
+    }
+
+    fn last(&self, n: usize) -> Vec<u8> {
+        let skip = self.lines.len().saturating_sub(n);
+        self.lines.iter().skip(skip).flatten().copied().collect()
+    }
+}
+
+static LOGS: Mutex<BTreeMap<String, Arc<Mutex<ProcessLog>>>> = Mutex::new(BTreeMap::new());
+
+fn log_for(name: &str) -> Arc<Mutex<ProcessLog>> {
+    LOGS.lock()
+        .unwrap()
+        .entry(name.to_string())
+        .or_insert_with(|| {
+            Arc::new(Mutex::new(ProcessLog {
+                lines: VecDeque::new(),
+                bytes: 0,
+                tx: broadcast::channel(256).0,
+            }))
+        })
+        .clone()
+}
+
+/// Copies `output` (a stdout or stderr pipe) into the buffer for `name` a line at a time,
+/// until it is closed. A restarted process keeps adding to the same buffer.
+pub fn capture(name: &str, output: impl AsyncRead + Send + Unpin + 'static) {
+    let log = log_for(name);
+    let name = name.to_string();
+    tokio::spawn(async move {
+        let mut reader = BufReader::new(output);
+        let mut stdout = tokio::io::stdout();
+        let mut line = vec![];
+        loop {
+            line.clear();
+            let read = (&mut reader)
+                .take(MAX_LINE as u64)
+                .read_until(b'\n', &mut line)
+                .await;
+            match read {
+                Ok(0) => return,
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Failed to read output of {}: {}", name, e);
+                    return;
+                }
+            }
+            stdout.write_all(&line).await.ok();
+            log.lock().unwrap().push(Bytes::copy_from_slice(&line));
+        }
+    });
+}
+
+/// The last `lines` lines captured from `name`, if anything was.
+pub fn last_lines(name: &str, lines: usize) -> Option<String> {
+    let log = LOGS.lock().unwrap().get(name).cloned()?;
+    let output = log.lock().unwrap().last(lines);
+    Some(String::from_utf8_lossy(&output).into_owned())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LogsQuery {
+    /// How many of the most recent lines to start with.
+    lines: Option<usize>,
+    /// Keep the response open and stream lines as they're written.
+    #[serde(default)]
+    follow: bool,
+}
+
+pub async fn handle_logs(name: String, query: LogsQuery) -> Result<Response, warp::Rejection> {
+    info!("logs: {} {:?}", name, query);
+    let log = LOGS.lock().unwrap().get(&name).cloned().ok_or_else(|| {
+        warp::reject::custom(ApiError::NotFound(format!(
+            "no output captured for {}",
+            name
+        )))
+    })?;
+    let lines = query.lines.unwrap_or(DEFAULT_LINES);
+
+    let body = {
+        // Subscribing under the lock means no line falls between the backlog and the follow.
+        let log = log.lock().unwrap();
+        let initial = log.last(lines);
+        if query.follow {
+            let rx = log.tx.subscribe();
+            let stream = futures::stream::unfold(
+                (Some(Bytes::from(initial)), rx),
+                |(initial, mut rx)| async move {
+                    if let Some(initial) = initial {
+                        return Some((Ok(initial), (None, rx)));
+                    }
+                    loop {
+                        match rx.recv().await {
+                            Ok(line) => return Some((Ok(line), (None, rx))),
+                            // A follower too slow for the process misses lines rather than
+                            // holding up the capture.
+                            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                            Err(broadcast::error::RecvError::Closed) => return None,
+                        }
+                    }
+                },
+            );
+            Body::wrap_stream::<_, _, io::Error>(stream)
+        } else {
+            Body::from(initial)
+        }
+    };
+    let mut response = Response::new(body);
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    Ok(response)
+}
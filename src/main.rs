@@ -1,46 +1,50 @@
-use base64::{engine::general_purpose, Engine as _};
-use core::net::Ipv4Addr;
-use futures::TryStreamExt;
-use log::{info, LevelFilter};
-use nix::mount::{mount, MsFlags};
+use cmdline::KernelCmdline;
+use config::RunConfig;
+use log::{error, info, warn, LevelFilter};
 use nix::sys::stat::Mode;
-use nix::sys::wait::{waitpid, WaitStatus};
-use nix::unistd::{chdir, chroot, mkdir, sethostname, symlinkat};
-use rtnetlink::new_connection;
+use ops::{mkdir, setdomainname, sethostname};
 use serde::{Deserialize, Serialize};
+use state::State;
 use std::env;
-use std::fs::write;
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, Write};
-use std::net::IpAddr;
-use tokio::process::Command;
-use tokio::signal::unix::{signal, SignalKind};
-use tokio_vsock::{VsockAddr, VsockListener};
-use warp::Filter;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 #[macro_use]
 pub mod macros;
+pub mod audit;
+pub mod cmdline;
+pub mod config;
+pub mod disks;
+pub mod dmesg;
+pub mod edit;
+pub mod entrypoint;
+pub mod envfile;
+pub mod etc;
+pub mod events;
+pub mod exec;
+pub mod files;
+pub mod health;
+pub mod loglevel;
+pub mod logs;
+pub mod mounts;
+pub mod net;
+pub mod notify;
+pub mod ops;
+pub mod ping;
+pub mod probe;
+pub mod process;
+pub mod ratelimit;
+pub mod reaper;
+pub mod reload;
+pub mod server;
+pub mod shutdown;
+pub mod sidecar;
+pub mod snapshot;
+pub mod state;
 pub mod sys;
-
-#[derive(Deserialize, Debug)]
-struct ExecRequest {
-    cmd: Vec<String>,
-}
-
-#[derive(Serialize)]
-struct ExecResponse {
-    output: String,
-}
-
-#[derive(Deserialize, Debug)]
-struct FileConfig {
-    guest_path: String,
-    raw_value: String,
-}
-
-#[derive(Deserialize, Debug)]
-struct RunConfig {
-    files: Vec<FileConfig>,
-}
+pub mod sysctl;
+pub mod tail;
+pub mod tls;
+pub mod wait;
 
 pub enum ApiReply<A, B> {
     Ok(A),
@@ -53,11 +57,10 @@ where
     B: warp::Reply,
 {
     fn into_response(self) -> warp::reply::Response {
-        let res = match self {
+        match self {
             ApiReply::Ok(a) => a.into_response(),
             ApiReply::Err(b) => b.into_response(),
-        };
-        res
+        }
     }
 }
 
@@ -66,144 +69,238 @@ pub struct ErrorMessage {
     message: String,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let log_level = match env::var("RUST_LOG") {
-        Ok(level) if level.to_lowercase() == "debug" => LevelFilter::Debug,
+/// Errors a handler can reject with. `server::handle_rejection` turns these into a status
+/// code and an `ErrorMessage` body.
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    Unauthorized(String),
+    NotFound(String),
+    Internal(String),
+    Unavailable(String),
+    TooManyRequests(String),
+}
+
+impl warp::reject::Reject for ApiError {}
+
+impl ApiError {
+    pub fn message(&self) -> &str {
+        match self {
+            ApiError::BadRequest(m)
+            | ApiError::Unauthorized(m)
+            | ApiError::NotFound(m)
+            | ApiError::Internal(m)
+            | ApiError::Unavailable(m)
+            | ApiError::TooManyRequests(m) => m,
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    let dry_run = env::args().any(|a| a == "--dry-run") || env::var_os("INIT_DRY_RUN").is_some();
+    ops::set_dry_run(dry_run);
+    let cmdline = KernelCmdline::read();
+    let log_level = match (cmdline.log_level, env::var("RUST_LOG")) {
+        (Some(level), _) => level,
+        (None, Ok(level)) if level.to_lowercase() == "debug" => LevelFilter::Debug,
         _ => LevelFilter::Info,
     };
-    env_logger::builder().filter_level(log_level).init();
-    let common_mnt_flags: MsFlags = MsFlags::MS_NODEV | MsFlags::MS_NOEXEC | MsFlags::MS_NOSUID;
-    let chmod_0755: Mode =
-        Mode::S_IRWXU | Mode::S_IRGRP | Mode::S_IXGRP | Mode::S_IROTH | Mode::S_IXOTH;
-    let chmod_1777: Mode = Mode::S_IRWXU | Mode::S_IRWXG | Mode::S_IRWXO | Mode::S_ISVTX;
+    loglevel::init(log_level);
+    for arg in &cmdline.unknown {
+        warn!("Ignoring unrecognized kernel command line setting: {}", arg);
+    }
 
-    let file = File::open("/firestarter/run.json")?;
-    let reader = BufReader::new(file);
-    let run_config: RunConfig = serde_json::from_reader(reader)?;
-    info!("Run configuration: {:?}", run_config);
+    let pid1 = is_pid1();
+    if dry_run {
+        info!("Dry run: system operations will be logged, not performed");
+    } else if !pid1 {
+        warn!(
+            "Not running as PID 1 (pid {}), system setup will be skipped",
+            std::process::id()
+        );
+    }
 
-    info!("Creating /dev directory...");
-    mkdir("/dev", Mode::S_IRWXU)?;
+    let mut run_config = RunConfig::load(&config::run_config_path(cmdline.config_path.as_deref()))?;
+    cmdline.apply(&mut run_config);
+    run_config.server.apply_env()?;
+    info!("Run configuration: {:?}", run_config.redacted());
 
-    info!("Mounting devtmpfs inside /dev...");
-    mount(
-        Some("devtmpfs"),
-        "/dev",
-        Some("devtmpfs"),
-        MsFlags::empty(),
-        None::<&str>,
-    )?;
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(threads) = worker_threads(&run_config)? {
+        info!("Running with {} worker threads", threads);
+        runtime.worker_threads(threads);
+    }
+    runtime
+        .build()?
+        .block_on(run(run_config, started, dry_run, pid1))
+}
 
-    info!("Creating /newroot directory...");
-    mkdir("/newroot", Mode::S_IRWXU)?;
+/// The worker thread count from `INIT_WORKER_THREADS` or the run config. Without either,
+/// tokio starts one per CPU.
+fn worker_threads(run_config: &RunConfig) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+    let threads = match env::var("INIT_WORKER_THREADS") {
+        Ok(threads) => Some(
+            threads
+                .parse()
+                .map_err(|e| format!("invalid INIT_WORKER_THREADS {:?}: {}", threads, e))?,
+        ),
+        Err(_) => run_config.worker_threads,
+    };
+    if threads == Some(0) {
+        return Err("worker_threads must be at least 1".into());
+    }
+    Ok(threads)
+}
 
-    info!("Mounting the root filesystem...");
-    mount(
-        Some("/dev/vdb"),
-        "/newroot",
-        Some("ext4"),
-        MsFlags::empty(),
-        None::<&str>,
-    )?;
+async fn run(
+    mut run_config: RunConfig,
+    started: Instant,
+    dry_run: bool,
+    pid1: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mask = run_config.umask.unwrap_or(0o022);
+    info!("Setting umask to {:04o}", mask);
+    ops::umask(Mode::from_bits_truncate(mask));
+
+    if dry_run {
+        mounts::mount_root(&run_config)?;
+        mounts::create_devices(&run_config.devices)?;
+        mounts::mount_system(&run_config.mounts)?;
+        mounts::register_binfmt(&run_config.mounts, &run_config.binfmt);
+        let state = State::new(run_config.clone());
+        setup_guest(&mut run_config, &state).await?;
+        info!("Dry run complete");
+        return Ok(());
+    }
 
-    // Move /dev so we don't have to re-mount it
-    info!("Mounting (move) /dev");
-    mkdir("/newroot/dev", Mode::S_IRWXU).ok();
-    mount::<_, _, [u8], [u8]>(Some("/dev"), "/newroot/dev", None, MsFlags::MS_MOVE, None)?;
+    let mut recovery = None;
+    if pid1 {
+        recovery = mounts::mount_root(&run_config)?;
+        mounts::create_devices(&run_config.devices)?;
+        mounts::mount_system(&run_config.mounts)?;
+        mounts::register_binfmt(&run_config.mounts, &run_config.binfmt);
+    }
 
-    info!("Switching the root filesystem...");
-    chdir("/newroot")?;
-    mount::<_, _, [u8], [u8]>(Some("."), "/", None, MsFlags::MS_MOVE, None)?;
-    // Change root to the current directory (new root)
-    chroot(".")?;
-    chdir("/")?;
-    mkdir("/dev/pts", chmod_0755).ok();
-    mount(
-        Some("devpts"),
-        "/dev/pts",
-        Some("devpts"),
-        MsFlags::MS_NOEXEC | MsFlags::MS_NOSUID | MsFlags::MS_NOATIME,
-        Some("mode=0620,gid=5,ptmxmode=666"),
-    )?;
+    // Serve the control API as early as possible so the guest can be inspected while the
+    // rest of setup runs; exec requests are refused until the state is marked ready.
+    let state = Arc::new(State::new(run_config.clone()));
+    let stuck_after =
+        (run_config.stuck_after_ms > 0).then(|| Duration::from_millis(run_config.stuck_after_ms));
+    if !pid1 && run_config.subreaper {
+        match reaper::set_subreaper() {
+            Ok(()) => info!("Reaping orphaned descendants as a child subreaper"),
+            Err(e) => warn!("could not become a child subreaper: {}", e),
+        }
+    }
+    reaper::start(run_config.reap_log_level, stuck_after);
+    let servers = server::spawn(&run_config.server, state.clone())?;
+
+    if let Some(problem) = recovery {
+        warn!("Recovery mode: only the control API is running");
+        state.set_recovery(problem);
+        state.set_ready();
+        events::phase("recovery");
+        let reason = shutdown::wait(None, servers).await;
+        info!("Shutting down: {}", reason);
+        shutdown::terminate_all().await;
+        shutdown::power_off();
+    }
 
-    info!("Mounting /dev/mqueue");
-    mkdir("/dev/mqueue", chmod_0755).ok();
-    mount::<_, _, _, [u8]>(
-        Some("mqueue"),
-        "/dev/mqueue",
-        Some("mqueue"),
-        common_mnt_flags,
-        None,
-    )?;
+    let mut networking = None;
+    if pid1 {
+        events::phase("guest_setup");
+        networking = Some(setup_guest(&mut run_config, &state).await?);
+    }
 
-    info!("Mounting /dev/shm");
-    mkdir("/dev/shm", chmod_1777).ok();
-    mount::<_, _, _, [u8]>(
-        Some("shm"),
-        "/dev/shm",
-        Some("tmpfs"),
-        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
-        None,
-    )?;
-    info!("Mounting /proc...");
-    mkdir("/proc", Mode::S_IRWXU).ok();
-    mount(
-        Some("proc"),
-        "/proc",
-        Some("proc"),
-        common_mnt_flags,
-        None::<&str>,
-    )?;
+    if run_config.sighup == config::SighupAction::Reload {
+        if let Err(e) = reload::reload_on_sighup(state.clone()) {
+            warn!("could not handle SIGHUP: {}", e);
+        }
+    }
+    if let Some(path) = run_config.watch_config.clone() {
+        if let Err(e) = reload::watch(path, state.clone()) {
+            warn!("could not watch config for changes: {}", e);
+        }
+    }
 
-    info!("Mounting /sys...");
-    mkdir("/sys", Mode::S_IRWXU).ok();
-    mount(
-        Some("sys"),
-        "/sys",
-        Some("sysfs"),
-        common_mnt_flags,
-        None::<&str>,
-    )?;
+    let mut entrypoint = None;
+    if pid1 {
+        if let Some(init_cmd) = &run_config.init_cmd {
+            let Err(e) = entrypoint::exec_init_cmd(&run_config, init_cmd);
+            error!("exec of init_cmd failed, staying resident: {:#}", e);
+        }
+        sidecar::start_all(&run_config, state.clone());
+        match entrypoint::spawn(&run_config, state.clone()) {
+            Ok(handle) => entrypoint = handle,
+            Err(e) => error!("{:#}", e),
+        }
+    }
 
-    info!("Mounting /run...");
-    mkdir("/run", Mode::S_IRWXU).ok();
-    mount(
-        Some("run"),
-        "/run",
-        Some("tmpfs"),
-        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
-        Some("mode=0755"),
-    )?;
-    mkdir("/run/lock", Mode::all()).ok();
+    state.set_ready();
+    events::phase("ready");
+    info!("Setup complete, init is ready");
+    let report = notify::BootReport::new(started, networking, entrypoint.is_some());
+    if let Some(config) = &run_config.ready_file {
+        notify::write_ready_file(config, &report);
+    }
+    if let Some(config) = &run_config.notify {
+        notify::send(config.clone(), report);
+    }
 
-    info!("Mounting /proc/sys/fs/binfmt_misc...");
-    mkdir("/proc/sys/fs/binfmt_misc", Mode::S_IRWXU).ok();
-    mount(
-        Some("binfmt_misc"),
-        "/proc/sys/fs/binfmt_misc",
-        Some("binfmt_misc"),
-        common_mnt_flags | MsFlags::MS_RELATIME,
-        None::<&str>,
-    )?;
+    let reason = shutdown::wait(entrypoint, servers).await;
+    info!("Shutting down: {}", reason);
+    if let Some(config) = &run_config.ready_file {
+        notify::remove_ready_file(config);
+    }
+    events::emit(events::Event::Phase {
+        phase: "shutting_down",
+        detail: Some(reason.to_string()),
+    });
+    shutdown::run_pre_stop(&state.config()).await;
+    if pid1 {
+        shutdown::stop_supervised().await;
+        shutdown::terminate_all().await;
+        events::phase("powering_off");
+        shutdown::power_off();
+    }
+    Ok(())
+}
 
-    symlinkat("/proc/self/fd", None, "/dev/fd").ok();
-    symlinkat("/proc/self/fd/0", None, "/dev/stdin").ok();
-    symlinkat("/proc/self/fd/1", None, "/dev/stdout").ok();
-    symlinkat("/proc/self/fd/2", None, "/dev/stderr").ok();
-    mkdir("/root", Mode::S_IRWXU).ok();
-    rlimit::setrlimit(rlimit::Resource::NOFILE, 10240, 10240).ok();
+/// Whether init is running as the guest's PID 1. Outside of that (under a test harness, or
+/// inside another container) the privileged system setup is skipped and only the control
+/// API is started.
+pub fn is_pid1() -> bool {
+    std::process::id() == 1
+}
 
-    for file_config in run_config.files {
-        let decoded_data = general_purpose::STANDARD.decode(&file_config.raw_value)?;
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(&file_config.guest_path)?;
-        file.write_all(&decoded_data)?;
-        info!("Saved file: {}", file_config.guest_path);
+/// Guest setup that runs after the control API is up: data volumes, files, /etc, hostname
+/// and networking. Returns whether networking came up.
+async fn setup_guest(
+    run_config: &mut RunConfig,
+    state: &State,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    mkdir("/root", Mode::S_IRWXU).ok();
+    let timeout = Duration::from_millis(run_config.wait_for_timeout_ms);
+    mounts::wait_for_paths(&run_config.wait_for, timeout).await;
+    mounts::mount_volumes(&run_config.volumes).await?;
+    if run_config.include.is_some() {
+        run_config.apply_include()?;
+        state.update_config(|config| {
+            config.image_config = run_config.image_config.clone();
+            config.files = run_config.files.clone();
+            config.symlinks = run_config.symlinks.clone();
+        });
     }
+    ops::setrlimit(rlimit::Resource::NOFILE, 10240, 10240).ok();
+
+    state.set_files_written(files::write_files(
+        &run_config.files,
+        run_config.files_root.as_deref(),
+        run_config.transactional_files,
+    )?);
+    files::create_symlinks(&run_config.symlinks)?;
 
     // let output = Command::new("cat").arg("file1.txt").output().await?;
     // info!(
@@ -211,133 +308,38 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     //     String::from_utf8_lossy(&output.stdout)
     // );
     info!("Creating /etc directory...");
-    mkdir("/etc", Mode::S_IRWXU).ok();
-
-    info!("Creating /etc/resolv.conf for DNS resolution...");
-    write("/etc/resolv.conf", "nameserver 8.8.8.8\n")?;
+    mkdir("/etc", Mode::from_bits_truncate(0o755)).ok();
 
-    info!("Creating /etc/hosts for local network resolution...");
-    write("/etc/hosts", "127.0.0.1 localhost\n")?;
+    etc::write_resolv_conf(run_config.etc_resolv.as_ref(), run_config.hosts_only_dns)?;
+    etc::write_hosts(run_config.etc_hosts.as_deref())?;
+    etc::write_nsswitch_conf(run_config.nsswitch.as_ref(), run_config.hosts_only_dns)?;
+    etc::install_ca_certs(&run_config.ca_certs).await?;
     info!("Setting hostname...");
-    match sethostname("hostname-1") {
-        Err(e) => info!("error setting hostname: {}", e),
-        Ok(_) => {}
-    };
-    configure_networking().await?;
-
-    let listener = VsockListener::bind(VsockAddr::new(3, 10000))?;
-    info!("Listening on vsock CID 3, port 10000");
-    let v1 = warp::path("v1");
-
-    let status_show = v1.and(warp::path("status"));
-    let get_status = warp::get().and(status_show).map(status);
-
-    let sysinfo_index = v1.and(warp::path("sysinfo"));
-
-    let get_sysinfo = warp::get().and(sysinfo_index).map(sys::list_sysinfo);
-    let post_exec = 
-        v1.and(warp::path("exec"))
-        .and(warp::post())
-        .and(warp::body::json())
-        .and_then(handle_exec);
-
-    tokio::spawn(async move {
-        warp::serve(combine!(
-            get_status,
-            get_sysinfo,
-            post_exec,
-        )).run_incoming(listener.incoming()).await;
-    });
-
-    // Spawn a task to reap zombie processes
-    tokio::spawn(async {
-        let mut sigchld = signal(SignalKind::child()).expect("Failed to create signal handler");
-        loop {
-            sigchld.recv().await;
-            while let Ok(WaitStatus::Exited(pid, _)) = waitpid(None, None) {
-                info!("Reaped zombie process with PID: {}", pid);
-            }
+    if let Err(e) = sethostname(run_config.hostname.as_deref().unwrap_or("hostname-1")) {
+        info!("error setting hostname: {}", e);
+    }
+    if let Some(domainname) = &run_config.domainname {
+        info!("Setting domainname...");
+        if let Err(e) = setdomainname(domainname) {
+            info!("error setting domainname: {}", e);
         }
-    });
-
-    loop {
-        tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
     }
-
-    // Ok(())
+    sysctl::apply(&run_config.sysctls);
+    net::configure_forwarding(&run_config.network);
+    let ip_configs = run_config.ip_configs.as_deref().unwrap_or_default();
+    let mut networking =
+        net::configure_networking_with_retry(ip_configs, &run_config.network).await;
+    if let (true, Some(check)) = (networking, &run_config.network.gateway_check) {
+        networking = net::check_gateways(ip_configs, &run_config.network, check).await;
+    }
+    if let Some(interface) = &run_config.network.masquerade {
+        if let Err(e) = net::configure_masquerade(interface).await {
+            error!("Failed to set up masquerading: {:#}", e);
+        }
+    }
+    Ok(networking)
 }
 
 pub fn status() -> impl warp::Reply {
     warp::reply::json(&serde_json::json!({"ok": true}))
 }
-
-async fn configure_networking() -> Result<(), Box<dyn std::error::Error>> {
-    let (connection, handle, _) = new_connection().unwrap();
-    tokio::spawn(connection);
-
-    info!("netlink: getting lo link");
-    let lo = handle
-        .link()
-        .get()
-        .match_name("lo".into())
-        .execute()
-        .try_next()
-        .await?
-        .expect("no lo link found");
-
-    info!("netlink: setting lo link \"up\"");
-    handle.link().set(lo.header.index).up().execute().await?;
-
-    info!("netlink: getting eth0 link");
-    let eth0 = handle
-        .link()
-        .get()
-        .match_name("eth0".into())
-        .execute()
-        .try_next()
-        .await?
-        .expect("no eth0 link found");
-
-    info!("netlink: setting eth0 link \"up\"");
-    handle
-        .link()
-        .set(eth0.header.index)
-        .up()
-        .mtu(1420)
-        .execute()
-        .await?;
-
-    let ip_address: IpAddr = "172.16.0.2".parse()?;
-    let gateway: Ipv4Addr = "172.16.0.1".parse()?;
-    info!("netlink: adding IP address to eth0");
-    handle
-        .address()
-        .add(eth0.header.index, ip_address, 24)
-        .execute()
-        .await?;
-
-    info!("netlink: adding default route via gateway");
-    handle.route().add().v4().gateway(gateway).execute().await?;
-
-    Ok(())
-}
-
-async fn handle_exec(req: ExecRequest) -> Result<impl warp::Reply, warp::Rejection> {
-    info!("Received request: {:?}", req);
-
-    let output = if req.cmd.len() > 0 {
-        let mut cmd = Command::new(&req.cmd[0]);
-        if req.cmd.len() > 1 {
-            cmd.args(&req.cmd[1..]);
-        }
-        match cmd.output().await {
-            Ok(output) => String::from_utf8_lossy(&output.stdout).to_string(),
-            Err(e) => format!("Failed to execute command: {}", e),
-        }
-    } else {
-        "No command provided".to_string()
-    };
-
-    let response = ExecResponse { output };
-    Ok(warp::reply::json(&response))
-}
@@ -0,0 +1,945 @@
+use super::config::{
+    Atime, CgroupMount, DeviceConfig, DeviceType, HidePid, MountsConfig, RootCheckConfig,
+    RootImageConfig, RootImageFormat, RunConfig, VolumeConfig, WipeConfig,
+};
+use super::ops::{self, chdir, chroot, create_dir_all, mkdir, mknod, mount, set_mode, symlink};
+use super::process;
+use anyhow::{anyhow, Context, Error};
+use log::{error, info, warn};
+use nix::mount::MsFlags;
+use nix::sys::stat::{Mode, SFlag};
+use nix::sys::statfs::statfs;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+const WAIT_FOR_INTERVAL: Duration = Duration::from_millis(50);
+
+const ATIME_FLAGS: MsFlags = MsFlags::MS_RELATIME
+    .union(MsFlags::MS_NOATIME)
+    .union(MsFlags::MS_STRICTATIME);
+
+fn atime_flag(atime: Atime) -> MsFlags {
+    match atime {
+        Atime::Relatime => MsFlags::MS_RELATIME,
+        Atime::Noatime => MsFlags::MS_NOATIME,
+        Atime::Strictatime => MsFlags::MS_STRICTATIME,
+    }
+}
+
+/// `flags` with the access time behaviour replaced by the one configured for `path`, if any.
+fn with_atime(mounts: &MountsConfig, path: &str, flags: MsFlags) -> MsFlags {
+    match mounts.atime.get(path) {
+        Some(&atime) => flags.difference(ATIME_FLAGS) | atime_flag(atime),
+        None => flags,
+    }
+}
+
+/// Options that can't be combined on one mount: the last one would silently win.
+const EXCLUSIVE_OPTIONS: &[&[&str]] = &[
+    &["ro", "rw"],
+    &["noatime", "relatime", "strictatime"],
+    &["barrier", "nobarrier"],
+    &["nodelalloc", "delalloc"],
+    &["journal_checksum", "nojournal_checksum"],
+];
+
+/// Parses `root_options`, refusing ones that contradict each other (`ro,rw`, or
+/// `data=ordered,data=writeback`), or an access time option when `mounts.atime` also sets
+/// one for `/`.
+fn root_mount_options(
+    mounts: &MountsConfig,
+    options: Option<&str>,
+) -> Result<(MsFlags, Option<String>), Error> {
+    let options = options.unwrap_or_default();
+    let given: Vec<&str> = options.split(',').filter(|o| !o.is_empty()).collect();
+    for group in EXCLUSIVE_OPTIONS {
+        let present: Vec<&str> = group
+            .iter()
+            .copied()
+            .filter(|o| given.contains(o))
+            .collect();
+        if present.len() > 1 {
+            return Err(anyhow!("conflicting root_options: {}", present.join(",")));
+        }
+    }
+    for (i, option) in given.iter().enumerate() {
+        let Some((key, value)) = option.split_once('=') else {
+            continue;
+        };
+        let conflict = given[..i]
+            .iter()
+            .filter_map(|o| o.split_once('='))
+            .find(|&(k, v)| k == key && v != value);
+        if let Some((_, other)) = conflict {
+            return Err(anyhow!(
+                "conflicting root_options: {}={},{}",
+                key,
+                other,
+                option
+            ));
+        }
+    }
+    if mounts.atime.contains_key("/") && given.iter().any(|o| EXCLUSIVE_OPTIONS[1].contains(o)) {
+        return Err(anyhow!(
+            "root_options set the access time behaviour, which mounts.atime also sets for /"
+        ));
+    }
+    let (flags, data) = parse_mount_options(options);
+    Ok((
+        with_atime(mounts, "/", flags),
+        (!data.is_empty()).then_some(data),
+    ))
+}
+
+/// Unpacks the root image into /newroot with `tar`, which has to be available before the
+/// switch to the new root. This runs before the reaper starts, so the helper is waited for
+/// directly.
+fn extract_root_image(image: &RootImageConfig) -> Result<(), Error> {
+    let mut args = vec!["-x", "-f", image.source.as_str(), "-C", "/newroot"];
+    match image.format {
+        RootImageFormat::TarZstd => args.push("--zstd"),
+        RootImageFormat::TarGzip => args.push("-z"),
+        RootImageFormat::Tar => {}
+    }
+    if ops::dry_run() {
+        info!("dry-run: run tar {}", args.join(" "));
+        return Ok(());
+    }
+    info!("Extracting the root image from {}...", image.source);
+    let started = std::time::Instant::now();
+    let output = process::blocking_helper_command("tar")
+        .args(&args)
+        .output()
+        .context("running tar")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "extracting the root image from {} failed ({}): {}",
+            image.source,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    info!("Extracted the root image in {:?}", started.elapsed());
+    Ok(())
+}
+
+/// A line of /proc/mounts.
+#[derive(Debug, Clone)]
+pub struct MountEntry {
+    pub source: String,
+    pub target: String,
+    pub fstype: String,
+}
+
+/// What is currently mounted, in mount order, from /proc/mounts. Empty when it can't be
+/// read, as before /proc is mounted.
+pub fn mount_entries() -> Vec<MountEntry> {
+    let mounts = std::fs::read_to_string("/proc/mounts").unwrap_or_default();
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ').map(unescape_mount_point);
+            Some(MountEntry {
+                source: fields.next()?,
+                target: fields.next()?,
+                fstype: fields.next()?,
+            })
+        })
+        .collect()
+}
+
+fn mount_points() -> Vec<String> {
+    mount_entries().into_iter().map(|m| m.target).collect()
+}
+
+/// Undoes the octal escaping of spaces, tabs, newlines and backslashes in /proc/mounts.
+fn unescape_mount_point(escaped: &str) -> String {
+    let mut out = vec![];
+    let bytes = escaped.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).and_then(|digits| {
+            let digits = std::str::from_utf8(digits).ok()?;
+            u8::from_str_radix(digits, 8).ok()
+        });
+        match (bytes[i], octal) {
+            (b'\\', Some(b)) => {
+                out.push(b);
+                i += 4;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn is_mounted(target: &str) -> bool {
+    let target = Path::new(target);
+    mount_points().iter().any(|m| Path::new(m) == target)
+}
+
+/// Mounts a filesystem on `target` unless something already is, so that setup can be run
+/// again (on a retry or a reload) without failing with EBUSY. Moves and remounts go through
+/// `ops::mount` directly.
+fn mount_new(
+    source: Option<&str>,
+    target: &str,
+    fstype: Option<&str>,
+    flags: MsFlags,
+    data: Option<&str>,
+) -> Result<(), Error> {
+    // A dry run reports every mount it would make, whatever the host has mounted.
+    if !ops::dry_run() && is_mounted(target) {
+        info!("{} is already mounted, leaving it", target);
+        return Ok(());
+    }
+    mount(source, target, fstype, flags, data)?;
+    Ok(())
+}
+
+/// Mounts devtmpfs and the root filesystem, then pivots into it.
+/// Returns the problem found by `root_check` when it falls back to recovery mode, in which
+/// case the root isn't switched and init stays in the initramfs.
+pub fn mount_root(run_config: &RunConfig) -> Result<Option<String>, Error> {
+    let mounts = &run_config.mounts;
+    let (flags, data) = root_mount_options(mounts, run_config.root_options.as_deref())?;
+
+    info!("Creating /dev directory...");
+    mkdir("/dev", Mode::from_bits_truncate(0o755))?;
+
+    mount_dev(mounts)?;
+
+    info!("Creating /newroot directory...");
+    mkdir("/newroot", Mode::from_bits_truncate(0o755))?;
+
+    info!(
+        "Mounting the root filesystem with flags {:?} and options {:?}...",
+        flags, data
+    );
+    match &run_config.root_image {
+        Some(image) => {
+            mount_new(
+                Some("tmpfs"),
+                "/newroot",
+                Some("tmpfs"),
+                flags,
+                data.as_deref(),
+            )?;
+            extract_root_image(image)?;
+        }
+        None => mount_new(
+            Some("/dev/vdb"),
+            "/newroot",
+            Some("ext4"),
+            flags,
+            data.as_deref(),
+        )?,
+    }
+
+    if let Some(check) = &run_config.root_check {
+        if let Err(e) = check_root(check) {
+            let problem = format!("the root filesystem mounted but looks broken: {:#}", e);
+            if check.recovery {
+                error!("{}", problem);
+                return Ok(Some(problem));
+            }
+            return Err(anyhow!(problem));
+        }
+    }
+
+    // Move /dev so we don't have to re-mount it
+    info!("Mounting (move) /dev");
+    mkdir("/newroot/dev", Mode::from_bits_truncate(0o755)).ok();
+    mount(Some("/dev"), "/newroot/dev", None, MsFlags::MS_MOVE, None)?;
+
+    info!("Switching the root filesystem...");
+    chdir("/newroot")?;
+    mount(Some("."), "/", None, MsFlags::MS_MOVE, None)?;
+    // Change root to the current directory (new root)
+    chroot(".")?;
+    chdir("/")?;
+    Ok(None)
+}
+
+fn check_root(check: &RootCheckConfig) -> Result<(), Error> {
+    if ops::dry_run() {
+        info!(
+            "dry-run: check the root filesystem for {:?}",
+            check.expect_paths
+        );
+        return Ok(());
+    }
+    let stats = statfs("/newroot").context("statfs")?;
+    if stats.blocks() == 0 {
+        return Err(anyhow!("statfs reports no blocks"));
+    }
+    std::fs::read_dir("/newroot")
+        .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+        .context("listing /")?;
+    for path in &check.expect_paths {
+        let inside = Path::new("/newroot").join(path.trim_start_matches('/'));
+        std::fs::symlink_metadata(&inside).with_context(|| format!("expected {}", path))?;
+    }
+    info!("Root filesystem check passed");
+    Ok(())
+}
+
+/// Character devices created when /dev has to be a plain tmpfs: path, major, minor and mode.
+const CORE_DEVICES: &[(&str, u64, u64, u32)] = &[
+    ("/dev/null", 1, 3, 0o666),
+    ("/dev/zero", 1, 5, 0o666),
+    ("/dev/full", 1, 7, 0o666),
+    ("/dev/random", 1, 8, 0o666),
+    ("/dev/urandom", 1, 9, 0o666),
+    ("/dev/kmsg", 1, 11, 0o644),
+    ("/dev/tty", 5, 0, 0o666),
+    ("/dev/console", 5, 1, 0o600),
+    ("/dev/ptmx", 5, 2, 0o666),
+];
+
+fn mount_dev(mounts: &MountsConfig) -> Result<(), Error> {
+    info!("Mounting devtmpfs inside /dev...");
+    let e = match mount_new(
+        Some("devtmpfs"),
+        "/dev",
+        Some("devtmpfs"),
+        MsFlags::empty(),
+        None,
+    ) {
+        Ok(()) => return Ok(()),
+        Err(e) if mounts.dev_fallback => e,
+        Err(e) => return Err(e.context("mounting devtmpfs")),
+    };
+    warn!(
+        "Can't mount devtmpfs ({:#}), falling back to a tmpfs /dev with static device nodes",
+        e
+    );
+    mount_new(
+        Some("dev"),
+        "/dev",
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID,
+        Some("mode=0755"),
+    )?;
+    for &(path, major, minor, mode) in CORE_DEVICES {
+        make_node(path, SFlag::S_IFCHR, major, minor, mode)?;
+    }
+    let block = create_block_devices().context("creating block device nodes")?;
+    info!(
+        "Created {} device nodes and block devices {:?} in /dev",
+        CORE_DEVICES.len(),
+        block
+    );
+    Ok(())
+}
+
+fn make_node(path: &str, kind: SFlag, major: u64, minor: u64, mode: u32) -> Result<(), Error> {
+    mknod(path, kind, Mode::from_bits_truncate(mode), major, minor)
+        .with_context(|| format!("creating device node {}", path))?;
+    // mknod's mode is filtered through the umask; set it explicitly.
+    set_mode(path, mode)?;
+    Ok(())
+}
+
+/// Creates a node for each block device the kernel lists in sysfs (so the root device can
+/// be mounted), mounting sysfs just long enough to read them. Returns their names.
+fn create_block_devices() -> Result<Vec<String>, Error> {
+    mkdir("/sys", Mode::from_bits_truncate(0o555)).ok();
+    mount(Some("sys"), "/sys", Some("sysfs"), MsFlags::empty(), None)?;
+    let devices = block_devices();
+    ops::umount("/sys")?;
+    let mut names = vec![];
+    for (name, major, minor) in devices? {
+        make_node(
+            &format!("/dev/{}", name),
+            SFlag::S_IFBLK,
+            major,
+            minor,
+            0o660,
+        )?;
+        names.push(name);
+    }
+    Ok(names)
+}
+
+// Block devices as (name, major, minor), from /sys/class/block/<name>/dev.
+fn block_devices() -> Result<Vec<(String, u64, u64)>, Error> {
+    let mut devices = vec![];
+    for entry in std::fs::read_dir("/sys/class/block")? {
+        let entry = entry?;
+        let dev = std::fs::read_to_string(entry.path().join("dev"))?;
+        let (major, minor) = dev
+            .trim()
+            .split_once(':')
+            .and_then(|(major, minor)| Some((major.parse().ok()?, minor.parse().ok()?)))
+            .ok_or_else(|| anyhow!("unexpected device number {:?}", dev.trim()))?;
+        devices.push((
+            entry.file_name().to_string_lossy().into_owned(),
+            major,
+            minor,
+        ));
+    }
+    devices.sort();
+    Ok(devices)
+}
+
+/// Creates the configured device nodes that devtmpfs didn't already populate.
+pub fn create_devices(devices: &[DeviceConfig]) -> Result<(), Error> {
+    for device in devices {
+        if Path::new(&device.path).exists() {
+            continue;
+        }
+        let kind = match device.kind {
+            DeviceType::Char => SFlag::S_IFCHR,
+            DeviceType::Block => SFlag::S_IFBLK,
+        };
+        info!(
+            "Creating device node {} ({}:{})",
+            device.path, device.major, device.minor
+        );
+        let mode = device.mode.unwrap_or(0o666);
+        make_node(&device.path, kind, device.major, device.minor, mode)?;
+    }
+    Ok(())
+}
+
+// Set when /proc or /sys failed to mount. The endpoints that read them answer 503 instead.
+static PROC_MISSING: AtomicBool = AtomicBool::new(false);
+static SYS_MISSING: AtomicBool = AtomicBool::new(false);
+
+pub fn proc_mounted() -> bool {
+    !PROC_MISSING.load(Ordering::Relaxed)
+}
+
+pub fn sys_mounted() -> bool {
+    !SYS_MISSING.load(Ordering::Relaxed)
+}
+
+/// The pseudo filesystems boot carried on without, for `/v1/health`.
+pub fn missing_mounts() -> Vec<&'static str> {
+    let mut missing = vec![];
+    if !proc_mounted() {
+        missing.push("/proc");
+    }
+    if !sys_mounted() {
+        missing.push("/sys");
+    }
+    missing
+}
+
+/// Mounts the pseudo filesystems inside the new root. Mounts disabled in `mounts` are
+/// skipped; nothing else mounted here depends on them, since `binfmt_misc` only needs
+/// `/proc` and `/proc` can't be disabled.
+pub fn mount_system(mounts: &MountsConfig) -> Result<(), Error> {
+    let common_mnt_flags: MsFlags = MsFlags::MS_NODEV | MsFlags::MS_NOEXEC | MsFlags::MS_NOSUID;
+
+    if mounts.dev_pts {
+        info!("Mounting /dev/pts");
+        mount_point(mounts, "/dev/pts", 0o755);
+        mount_new(
+            Some("devpts"),
+            "/dev/pts",
+            Some("devpts"),
+            with_atime(
+                mounts,
+                "/dev/pts",
+                MsFlags::MS_NOEXEC | MsFlags::MS_NOSUID | MsFlags::MS_NOATIME,
+            ),
+            Some("mode=0620,gid=5,ptmxmode=666"),
+        )?;
+    }
+
+    if mounts.dev_mqueue {
+        info!("Mounting /dev/mqueue");
+        mount_point(mounts, "/dev/mqueue", 0o1777);
+        mount_new(
+            Some("mqueue"),
+            "/dev/mqueue",
+            Some("mqueue"),
+            with_atime(mounts, "/dev/mqueue", common_mnt_flags),
+            None,
+        )?;
+    }
+
+    if mounts.dev_shm {
+        info!("Mounting /dev/shm");
+        mount_point(mounts, "/dev/shm", 0o1777);
+        mount_new(
+            Some("shm"),
+            "/dev/shm",
+            Some("tmpfs"),
+            with_atime(mounts, "/dev/shm", MsFlags::MS_NOSUID | MsFlags::MS_NODEV),
+            None,
+        )?;
+    }
+
+    // Init itself gets by without /proc and /sys, so failing to mount them leaves the guest
+    // reachable to debug rather than failing boot.
+    let proc_options = proc_options(mounts);
+    info!("Mounting /proc with options {:?}...", proc_options);
+    mount_point(mounts, "/proc", 0o555);
+    if let Err(e) = mount_new(
+        Some("proc"),
+        "/proc",
+        Some("proc"),
+        with_atime(mounts, "/proc", common_mnt_flags),
+        proc_options.as_deref(),
+    ) {
+        error!("Failed to mount /proc, carrying on without it: {:#}", e);
+        PROC_MISSING.store(true, Ordering::Relaxed);
+    }
+
+    info!("Mounting /sys...");
+    mount_point(mounts, "/sys", 0o555);
+    if let Err(e) = mount_new(
+        Some("sys"),
+        "/sys",
+        Some("sysfs"),
+        with_atime(mounts, "/sys", common_mnt_flags),
+        None,
+    ) {
+        error!("Failed to mount /sys, carrying on without it: {:#}", e);
+        SYS_MISSING.store(true, Ordering::Relaxed);
+    }
+
+    if mounts.cgroup != CgroupMount::Skip && !sys_mounted() {
+        warn!("Not mounting /sys/fs/cgroup without /sys");
+    } else if mounts.cgroup != CgroupMount::Skip {
+        let mut flags = common_mnt_flags;
+        if mounts.cgroup == CgroupMount::Ro {
+            flags |= MsFlags::MS_RDONLY;
+        }
+        info!("Mounting /sys/fs/cgroup ({:?})...", mounts.cgroup);
+        mount_point(mounts, "/sys/fs/cgroup", 0o555);
+        mount_new(
+            Some("cgroup2"),
+            "/sys/fs/cgroup",
+            Some("cgroup2"),
+            with_atime(mounts, "/sys/fs/cgroup", flags),
+            None,
+        )?;
+    }
+
+    let kernel_filesystems = [
+        (mounts.debugfs, "debugfs", "/sys/kernel/debug"),
+        (mounts.tracefs, "tracefs", "/sys/kernel/tracing"),
+        (mounts.configfs, "configfs", "/sys/kernel/config"),
+    ];
+    for (_, fstype, target) in kernel_filesystems.iter().filter(|(enabled, ..)| *enabled) {
+        if !sys_mounted() {
+            warn!("Not mounting {} without /sys", fstype);
+            continue;
+        }
+        info!("Mounting {} on {}...", fstype, target);
+        // One the kernel wasn't built with is only logged; nothing depends on these.
+        if let Err(e) = mount_new(
+            Some(fstype),
+            target,
+            Some(fstype),
+            with_atime(mounts, target, common_mnt_flags),
+            None,
+        ) {
+            error!("Failed to mount {} on {}: {:#}", fstype, target, e);
+        }
+    }
+
+    if mounts.run {
+        info!("Mounting /run...");
+        mount_point(mounts, "/run", 0o755);
+        mount_new(
+            Some("run"),
+            "/run",
+            Some("tmpfs"),
+            with_atime(mounts, "/run", MsFlags::MS_NOSUID | MsFlags::MS_NODEV),
+            Some("mode=0755"),
+        )?;
+    }
+    mount_point(mounts, "/run/lock", 0o1777);
+
+    if mounts.binfmt_misc && !proc_mounted() {
+        warn!("Not mounting binfmt_misc without /proc");
+    } else if mounts.binfmt_misc {
+        info!("Mounting /proc/sys/fs/binfmt_misc...");
+        mount_point(mounts, "/proc/sys/fs/binfmt_misc", 0o755);
+        mount_new(
+            Some("binfmt_misc"),
+            "/proc/sys/fs/binfmt_misc",
+            Some("binfmt_misc"),
+            with_atime(
+                mounts,
+                "/proc/sys/fs/binfmt_misc",
+                common_mnt_flags | MsFlags::MS_RELATIME,
+            ),
+            None,
+        )?;
+    }
+
+    if let Some(size) = &mounts.var_log_size {
+        info!("Mounting /var/log (tmpfs, size {})...", size);
+        create_dir_all(Path::new("/var"))?;
+        mount_point(mounts, "/var/log", 0o755);
+        mount_new(
+            Some("tmpfs"),
+            "/var/log",
+            Some("tmpfs"),
+            with_atime(mounts, "/var/log", MsFlags::MS_NOSUID | MsFlags::MS_NODEV),
+            Some(&format!("size={},mode=0755", size)),
+        )?;
+    }
+
+    symlink("/proc/self/fd", "/dev/fd").ok();
+    symlink("/proc/self/fd/0", "/dev/stdin").ok();
+    symlink("/proc/self/fd/1", "/dev/stdout").ok();
+    symlink("/proc/self/fd/2", "/dev/stderr").ok();
+    Ok(())
+}
+
+const BINFMT_REGISTER: &str = "/proc/sys/fs/binfmt_misc/register";
+
+/// Registers the `binfmt` interpreters with binfmt_misc. An entry that is malformed or that
+/// the kernel rejects is logged and skipped.
+pub fn register_binfmt(mounts: &MountsConfig, entries: &[String]) {
+    if entries.is_empty() {
+        return;
+    }
+    if !mounts.binfmt_misc || !proc_mounted() {
+        warn!("binfmt entries are configured but binfmt_misc isn't mounted, skipping them");
+        return;
+    }
+    for entry in entries {
+        let name = match check_binfmt(entry) {
+            Ok(name) => name,
+            Err(e) => {
+                error!("Not registering binfmt entry {:?}: {}", entry, e);
+                continue;
+            }
+        };
+        info!("Registering binfmt interpreter {}", name);
+        if let Err(e) = ops::write(BINFMT_REGISTER, entry) {
+            error!("Failed to register binfmt interpreter {}: {}", name, e);
+        }
+    }
+}
+
+/// Checks an entry against binfmt_misc's `:name:type:offset:magic:mask:interpreter:flags`
+/// format (the first character is the separator, usually `:`) and returns its name.
+fn check_binfmt(entry: &str) -> Result<&str, String> {
+    // The kernel's limit on a registration string.
+    if entry.len() > 1920 {
+        return Err("longer than 1920 bytes".to_string());
+    }
+    let separator = entry.chars().next().ok_or("empty")?;
+    let fields: Vec<&str> = entry[separator.len_utf8()..].split(separator).collect();
+    let [name, kind, offset, magic, _mask, interpreter, flags] = fields[..] else {
+        return Err(format!("has {} fields, not 7", fields.len()));
+    };
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        return Err(format!("invalid name {:?}", name));
+    }
+    match kind {
+        "M" if magic.is_empty() => return Err("magic type without magic bytes".to_string()),
+        "M" => {}
+        "E" if !offset.is_empty() => return Err("extension type with an offset".to_string()),
+        "E" => {}
+        _ => return Err(format!("type {:?} isn't M or E", kind)),
+    }
+    if !offset.is_empty() && offset.parse::<u32>().is_err() {
+        return Err(format!("invalid offset {:?}", offset));
+    }
+    if interpreter.is_empty() {
+        return Err("no interpreter".to_string());
+    }
+    if let Some(flag) = flags.trim_end().chars().find(|f| !"POCF".contains(*f)) {
+        return Err(format!("unknown flag {:?}", flag));
+    }
+    Ok(name)
+}
+
+/// Waits until every path in `paths` exists, or `timeout` has passed. Paths still missing
+/// then are logged and left to whatever needs them to fail.
+pub async fn wait_for_paths(paths: &[String], timeout: Duration) {
+    if paths.is_empty() {
+        return;
+    }
+    if ops::dry_run() {
+        info!("dry-run: wait for {}", paths.join(", "));
+        return;
+    }
+    info!("Waiting for {}...", paths.join(", "));
+    let deadline = Instant::now() + timeout;
+    loop {
+        let missing: Vec<&str> = paths
+            .iter()
+            .map(String::as_str)
+            .filter(|p| !Path::new(p).exists())
+            .collect();
+        if missing.is_empty() {
+            return;
+        }
+        if Instant::now() >= deadline {
+            error!(
+                "Still missing after {:?}, carrying on: {}",
+                timeout,
+                missing.join(", ")
+            );
+            return;
+        }
+        sleep(WAIT_FOR_INTERVAL).await;
+    }
+}
+
+/// Checks and mounts the configured data volumes. A volume that fails stops boot only if it
+/// is marked `required`.
+pub async fn mount_volumes(volumes: &[VolumeConfig]) -> Result<(), Error> {
+    for volume in volumes {
+        if let Err(e) = mount_volume(volume).await {
+            if volume.required {
+                return Err(e);
+            }
+            error!("{:#}, continuing without it", e);
+        }
+    }
+    Ok(())
+}
+
+/// How much of each end of a device must be zeroes for it to count as blank. Partition
+/// tables, RAID and LVM metadata and filesystem superblocks all live in these regions.
+const BLANK_PROBE_SIZE: u64 = 1024 * 1024;
+
+/// Whether the device holds nothing but zeroes at its start and end.
+fn is_blank(device: &str) -> Result<bool, Error> {
+    let mut file = File::open(device)?;
+    let size = file.seek(SeekFrom::End(0))?;
+    let mut regions = vec![0];
+    if size > BLANK_PROBE_SIZE {
+        regions.push(size - BLANK_PROBE_SIZE);
+    }
+    let mut buf = vec![0; BLANK_PROBE_SIZE.min(size) as usize];
+    for start in regions {
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut buf)?;
+        if buf.iter().any(|&b| b != 0) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+async fn format_if_blank(volume: &VolumeConfig) -> Result<(), Error> {
+    if !is_blank(&volume.device).with_context(|| format!("probing {}", volume.device))? {
+        return Ok(());
+    }
+    info!(
+        "{} is blank, formatting it with mkfs.{}",
+        volume.device, volume.fstype
+    );
+    mkfs(volume, false).await
+}
+
+/// Runs `mkfs.<fstype>` on the volume. With `force` it goes ahead over an existing
+/// filesystem, which some mkfs tools otherwise refuse to do.
+async fn mkfs(volume: &VolumeConfig, force: bool) -> Result<(), Error> {
+    let mkfs = format!("mkfs.{}", volume.fstype);
+    let mut args = vec![];
+    if force {
+        match volume.fstype.as_str() {
+            "ext2" | "ext3" | "ext4" => args.push("-F"),
+            "xfs" | "btrfs" => args.push("-f"),
+            _ => {}
+        }
+    }
+    args.push(volume.device.as_str());
+    let (exit, output) = process::run_helper(&mkfs, &args)
+        .await
+        .with_context(|| format!("formatting volume {}", volume.device))?;
+    if exit.code != Some(0) {
+        return Err(anyhow!(
+            "{} failed on {} (exit code {:?}, signal {:?}): {}",
+            mkfs,
+            volume.device,
+            exit.code,
+            exit.signal,
+            output.trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Where on a wiped volume the reset it was wiped for is recorded.
+const WIPE_MARKER: &str = ".init-wipe-id";
+
+/// Reformats the volume unless it was already wiped for this reset. A volume that can't be
+/// mounted to check (a corrupt one, say) is wiped.
+async fn wipe_volume(volume: &VolumeConfig, wipe: &WipeConfig) -> Result<(), Error> {
+    if wipe.confirm != volume.device {
+        return Err(anyhow!(
+            "not wiping {}: wipe.confirm is {:?}, not the device",
+            volume.device,
+            wipe.confirm
+        ));
+    }
+    let marker = format!(
+        "{}/{}",
+        volume.mountpoint.trim_end_matches('/'),
+        WIPE_MARKER
+    );
+    create_dir_all(Path::new(&volume.mountpoint))?;
+    if !ops::dry_run() {
+        let mounted = mount(
+            Some(volume.device.as_str()),
+            &volume.mountpoint,
+            Some(volume.fstype.as_str()),
+            MsFlags::MS_RDONLY,
+            None,
+        );
+        if mounted.is_ok() {
+            let wiped_for = std::fs::read_to_string(&marker).ok();
+            ops::umount(&volume.mountpoint)?;
+            if wiped_for.as_deref() == Some(wipe.id.as_str()) {
+                info!(
+                    "{} was already wiped for reset {:?}, keeping it",
+                    volume.device, wipe.id
+                );
+                return Ok(());
+            }
+        }
+    }
+
+    warn!(
+        "WIPING volume {} for reset {:?}: reformatting it with mkfs.{}, its contents are lost",
+        volume.device, wipe.id, volume.fstype
+    );
+    mkfs(volume, true).await?;
+    mount(
+        Some(volume.device.as_str()),
+        &volume.mountpoint,
+        Some(volume.fstype.as_str()),
+        MsFlags::empty(),
+        None,
+    )
+    .with_context(|| format!("mounting {} to record the wipe", volume.device))?;
+    let recorded = ops::write(&marker, &wipe.id);
+    ops::umount(&volume.mountpoint)?;
+    recorded.with_context(|| format!("recording the wipe in {}", marker))?;
+    warn!("Wiped volume {}", volume.device);
+    Ok(())
+}
+
+async fn mount_volume(volume: &VolumeConfig) -> Result<(), Error> {
+    // Checked up front as well, so a mounted volume isn't checked or formatted.
+    if !ops::dry_run() && is_mounted(&volume.mountpoint) {
+        info!("{} is already mounted, leaving it", volume.mountpoint);
+        return Ok(());
+    }
+    if let Some(wipe) = &volume.wipe {
+        wipe_volume(volume, wipe).await?;
+    }
+    if volume.format_if_empty {
+        format_if_blank(volume).await?;
+    }
+    if volume.fsck {
+        let checker = format!("fsck.{}", volume.fstype);
+        let (exit, output) = process::run_helper(&checker, &["-p", &volume.device])
+            .await
+            .with_context(|| format!("checking volume {}", volume.device))?;
+        // fsck exit codes are a bitmask: 1 and 2 mean errors were found and fixed.
+        match exit.code {
+            Some(0) => {}
+            Some(code) if code < 4 => warn!(
+                "{} fixed errors on {} (exit code {}): {}",
+                checker,
+                volume.device,
+                code,
+                output.trim()
+            ),
+            _ => {
+                return Err(anyhow!(
+                    "{} failed on {} (exit code {:?}, signal {:?}): {}",
+                    checker,
+                    volume.device,
+                    exit.code,
+                    exit.signal,
+                    output.trim()
+                ))
+            }
+        }
+    }
+
+    info!(
+        "Mounting volume {} on {} ({})",
+        volume.device, volume.mountpoint, volume.fstype
+    );
+    create_dir_all(Path::new(&volume.mountpoint))?;
+    let (flags, data) = parse_mount_options(volume.options.as_deref().unwrap_or_default());
+    mount_new(
+        Some(volume.device.as_str()),
+        &volume.mountpoint,
+        Some(volume.fstype.as_str()),
+        flags,
+        (!data.is_empty()).then_some(data.as_str()),
+    )
+    .with_context(|| format!("mounting volume {} on {}", volume.device, volume.mountpoint))?;
+    Ok(())
+}
+
+/// The data string for /proc's `hidepid=` and `gid=`. The numeric hidepid values are used
+/// where there is one, since older kernels don't accept the names.
+fn proc_options(mounts: &MountsConfig) -> Option<String> {
+    let mut options = vec![];
+    if let Some(hidepid) = mounts.proc_hidepid {
+        options.push(match hidepid {
+            HidePid::Off => "hidepid=0".to_string(),
+            HidePid::Noaccess => "hidepid=1".to_string(),
+            HidePid::Invisible => "hidepid=2".to_string(),
+            HidePid::Ptraceable => "hidepid=ptraceable".to_string(),
+        });
+    }
+    if let Some(gid) = mounts.proc_gid {
+        options.push(format!("gid={}", gid));
+    }
+    (!options.is_empty()).then(|| options.join(","))
+}
+
+/// Splits fstab-style options into mount flags and the filesystem-specific data string.
+fn parse_mount_options(options: &str) -> (MsFlags, String) {
+    let mut flags = MsFlags::empty();
+    let mut data = vec![];
+    for option in options.split(',').filter(|o| !o.is_empty()) {
+        match option {
+            "ro" => flags |= MsFlags::MS_RDONLY,
+            "rw" => flags &= !MsFlags::MS_RDONLY,
+            "nosuid" => flags |= MsFlags::MS_NOSUID,
+            "nodev" => flags |= MsFlags::MS_NODEV,
+            "noexec" => flags |= MsFlags::MS_NOEXEC,
+            "sync" => flags |= MsFlags::MS_SYNCHRONOUS,
+            "noatime" => flags = flags.difference(ATIME_FLAGS) | MsFlags::MS_NOATIME,
+            "relatime" => flags = flags.difference(ATIME_FLAGS) | MsFlags::MS_RELATIME,
+            "strictatime" => flags = flags.difference(ATIME_FLAGS) | MsFlags::MS_STRICTATIME,
+            "nodiratime" => flags |= MsFlags::MS_NODIRATIME,
+            "defaults" => {}
+            other => data.push(other),
+        }
+    }
+    (flags, data.join(","))
+}
+
+/// Creates a mount point with the mode configured for it in `dir_modes`, or `default`. The
+/// mode is set explicitly because mkdir's is reduced by the umask. Directories that already
+/// exist keep their permissions unless a mode is configured for them.
+fn mount_point(mounts: &MountsConfig, path: &str, default: u32) {
+    let configured = mounts.dir_modes.get(path).copied();
+    let mode = configured.unwrap_or(default);
+    let created = mkdir(path, Mode::from_bits_truncate(mode)).is_ok();
+    if created || configured.is_some() {
+        if let Err(e) = set_mode(path, mode) {
+            warn!("Failed to set mode {:04o} on {}: {}", mode, path, e);
+        }
+    }
+}
@@ -0,0 +1,697 @@
+use super::config::{GatewayCheck, IpConfig, NetworkConfig, Offload};
+use super::{ops, ping, process, sysctl, ApiReply, ErrorMessage};
+use anyhow::{anyhow, Error};
+use futures::TryStreamExt;
+use log::{error, info, warn};
+use netlink_packet_route::address::AddressAttribute;
+use netlink_packet_route::link::{LinkAttribute, LinkFlag};
+use netlink_packet_route::route::{RouteAddress, RouteAttribute, RouteHeader, RouteMessage};
+use rtnetlink::{new_connection, Handle, IpVersion};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::Duration;
+use warp::http::StatusCode;
+
+const EEXIST: i32 = -17;
+const ROUTE_ATTEMPTS: u32 = 5;
+const ROUTE_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Configures networking, retrying the whole sequence on a fresh netlink connection if any
+/// step fails. Every step tolerates having already been done, so a retry picks up where the
+/// previous attempt left off. Returns whether networking ended up configured; a failure is
+/// logged rather than returned so init carries on and the guest stays reachable over vsock.
+pub async fn configure_networking_with_retry(
+    ip_configs: &[IpConfig],
+    network: &NetworkConfig,
+) -> bool {
+    // A bad MAC would fail every attempt; leave the address alone instead.
+    let mac = network.mac.as_deref().and_then(|mac| match parse_mac(mac) {
+        Ok(mac) => Some(mac),
+        Err(e) => {
+            error!("Not setting eth0's MAC address: {:#}", e);
+            None
+        }
+    });
+    let attempts = network.attempts.max(1);
+    let mut backoff = Duration::from_millis(network.initial_backoff_ms);
+    for attempt in 1..=attempts {
+        match configure_networking(ip_configs, network, mac).await {
+            Ok(()) => return true,
+            Err(e) if attempt < attempts => {
+                warn!(
+                    "Configuring networking failed (attempt {}/{}): {:#}, retrying in {:?}",
+                    attempt, attempts, e, backoff
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(e) => error!(
+                "Configuring networking failed after {} attempts, continuing without it: {:#}",
+                attempts, e
+            ),
+        }
+    }
+    false
+}
+
+/// Pings each default gateway and returns whether they all answered. With `check.wait` it
+/// keeps at it, a second apart, until they do.
+pub async fn check_gateways(
+    ip_configs: &[IpConfig],
+    network: &NetworkConfig,
+    check: &GatewayCheck,
+) -> bool {
+    let gateways = match default_gateways(ip_configs, network) {
+        Ok(gateways) => gateways,
+        Err(e) => {
+            error!("Not checking the gateways: {:#}", e);
+            return false;
+        }
+    };
+    if ops::dry_run() {
+        for gateway in &gateways {
+            info!("dry-run: ping gateway {}", gateway);
+        }
+        return true;
+    }
+    let timeout = Duration::from_millis(check.timeout_ms);
+    loop {
+        let mut all_answered = true;
+        for &gateway in &gateways {
+            all_answered &= ping_gateway(gateway, check.count.max(1), timeout).await;
+        }
+        if all_answered || !check.wait {
+            return all_answered;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn ping_gateway(gateway: IpAddr, count: u16, timeout: Duration) -> bool {
+    let mut answered = vec![];
+    for seq in 1..=count {
+        match tokio::task::spawn_blocking(move || ping::echo(gateway, seq, timeout)).await {
+            Ok(Ok(rtt)) => answered.push(rtt),
+            Ok(Err(e)) => info!("ping {} (seq {}): {}", gateway, seq, e),
+            Err(e) => info!("ping {} (seq {}): {}", gateway, seq, e),
+        }
+    }
+    if answered.is_empty() {
+        warn!("Gateway {} didn't answer any of {} pings", gateway, count);
+        return false;
+    }
+    let average = answered.iter().sum::<Duration>() / answered.len() as u32;
+    info!(
+        "Gateway {} answered {} of {} pings (average {:?})",
+        gateway,
+        answered.len(),
+        count,
+        average
+    );
+    true
+}
+
+/// Turns on forwarding if configured. Runs before the interfaces come up, so no packet
+/// arrives before the guest is ready to route it.
+pub fn configure_forwarding(network: &NetworkConfig) {
+    if !network.ip_forward {
+        return;
+    }
+    for key in ["net.ipv4.ip_forward", "net.ipv6.conf.all.forwarding"] {
+        if let Err(e) = sysctl::set(key, "1") {
+            error!("Failed to enable forwarding ({}): {:#}", key, e);
+        }
+    }
+    info!("Enabled IP forwarding");
+}
+
+/// Adds a masquerade rule for traffic leaving through `interface`, unless it already exists.
+pub async fn configure_masquerade(interface: &str) -> Result<(), Error> {
+    let rule = ["POSTROUTING", "-o", interface, "-j", "MASQUERADE"];
+    let check = [&["-t", "nat", "-C"][..], &rule].concat();
+    let (exit, _) = process::run_helper("iptables", &check).await?;
+    if exit.code == Some(0) && !ops::dry_run() {
+        info!("Masquerade rule for {} already present", interface);
+        return Ok(());
+    }
+    let add = [&["-t", "nat", "-A"][..], &rule].concat();
+    let (exit, output) = process::run_helper("iptables", &add).await?;
+    if exit.code != Some(0) {
+        return Err(anyhow!(
+            "iptables failed (code {:?}): {}",
+            exit.code,
+            output.trim()
+        ));
+    }
+    info!("Masquerading traffic out of {}", interface);
+    Ok(())
+}
+
+/// Parses a MAC address written as six colon-separated hex octets.
+fn parse_mac(mac: &str) -> Result<[u8; 6], Error> {
+    let invalid = || anyhow!("invalid MAC address {:?}, expected aa:bb:cc:dd:ee:ff", mac);
+    let mut octets = [0; 6];
+    let mut parts = mac.split(':');
+    for octet in &mut octets {
+        let part = parts.next().ok_or_else(invalid)?;
+        if part.len() != 2 {
+            return Err(invalid());
+        }
+        *octet = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+    }
+    if parts.next().is_some() {
+        return Err(invalid());
+    }
+    // Bit 0 of the first octet marks a multicast address, which an interface can't have.
+    if octets[0] & 1 != 0 {
+        return Err(anyhow!(
+            "invalid MAC address {:?}: multicast bit is set",
+            mac
+        ));
+    }
+    Ok(octets)
+}
+
+fn format_mac(mac: &[u8; 6]) -> String {
+    mac.iter()
+        .map(|octet| format!("{:02x}", octet))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+pub async fn configure_networking(
+    ip_configs: &[IpConfig],
+    network: &NetworkConfig,
+    mac: Option<[u8; 6]>,
+) -> Result<(), Error> {
+    let gateways = default_gateways(ip_configs, network)?;
+    if ops::dry_run() {
+        if let Some(mac) = &mac {
+            info!("dry-run: set eth0 MAC address to {}", format_mac(mac));
+        }
+        info!("dry-run: bring up lo and eth0 (mtu 1420)");
+        if let Some(len) = network.txqueuelen {
+            info!("dry-run: set eth0 txqueuelen to {}", len);
+        }
+        for (offload, enabled) in &network.offloads {
+            info!("dry-run: turn {:?} {} on eth0", offload, on_off(*enabled));
+        }
+        for ip_config in ip_configs {
+            info!(
+                "dry-run: add address {}/{} to eth0",
+                ip_config.address()?,
+                ip_config.mask
+            );
+        }
+        log_default_routes(&gateways);
+        return Ok(());
+    }
+
+    let (connection, handle, _) = new_connection()?;
+    tokio::spawn(connection);
+
+    info!("netlink: getting lo link");
+    let lo = handle
+        .link()
+        .get()
+        .match_name("lo".into())
+        .execute()
+        .try_next()
+        .await?
+        .ok_or_else(|| anyhow!("no lo link found"))?;
+
+    info!("netlink: setting lo link \"up\"");
+    handle.link().set(lo.header.index).up().execute().await?;
+
+    info!("netlink: getting eth0 link");
+    let eth0 = handle
+        .link()
+        .get()
+        .match_name("eth0".into())
+        .execute()
+        .try_next()
+        .await?
+        .ok_or_else(|| anyhow!("no eth0 link found"))?;
+
+    // Set before bringing the link up; some drivers refuse to change it on a running link.
+    if let Some(mac) = &mac {
+        info!("netlink: setting eth0 MAC address to {}", format_mac(mac));
+        handle
+            .link()
+            .set(eth0.header.index)
+            .address(mac.to_vec())
+            .execute()
+            .await?;
+    }
+
+    info!("netlink: setting eth0 link \"up\"");
+    handle
+        .link()
+        .set(eth0.header.index)
+        .up()
+        .mtu(1420)
+        .execute()
+        .await?;
+
+    tune_link(&handle, eth0.header.index, network).await;
+    add_addresses(&handle, eth0.header.index, ip_configs).await?;
+
+    for gateway in gateways {
+        add_default_route(&handle, gateway, false).await?;
+    }
+    Ok(())
+}
+
+fn on_off(enabled: bool) -> &'static str {
+    if enabled {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+/// Applies the configured txqueuelen and offloads to eth0. These are tuning, so a setting the
+/// driver rejects is logged rather than failing networking.
+async fn tune_link(handle: &Handle, index: u32, network: &NetworkConfig) {
+    if let Some(len) = network.txqueuelen {
+        let mut request = handle.link().set(index);
+        request
+            .message_mut()
+            .attributes
+            .push(LinkAttribute::TxQueueLen(len));
+        match request.execute().await {
+            Ok(()) => info!("netlink: set eth0 txqueuelen to {}", len),
+            Err(e) => warn!("eth0 rejected txqueuelen {}: {}", len, e),
+        }
+    }
+    for (&offload, &enabled) in &network.offloads {
+        match set_offload("eth0", offload, enabled) {
+            Ok(()) => info!("Turned {:?} {} on eth0", offload, on_off(enabled)),
+            Err(e) => warn!(
+                "eth0's driver rejected turning {:?} {}: {}",
+                offload,
+                on_off(enabled),
+                e
+            ),
+        }
+    }
+}
+
+// SIOCETHTOOL and the legacy ETHTOOL_S* commands from linux/sockios.h and linux/ethtool.h,
+// which the kernel still maps onto the corresponding features.
+const SIOCETHTOOL: libc::c_ulong = 0x8946;
+const ETHTOOL_STSO: u32 = 0x1f;
+const ETHTOOL_SGSO: u32 = 0x24;
+const ETHTOOL_SGRO: u32 = 0x2c;
+
+#[repr(C)]
+struct EthtoolValue {
+    cmd: u32,
+    data: u32,
+}
+
+// struct ifreq, with the ifr_data member of its union.
+#[repr(C)]
+struct IfReqData {
+    name: [libc::c_char; libc::IFNAMSIZ],
+    data: *mut libc::c_void,
+    _rest: [u8; 16],
+}
+
+fn set_offload(interface: &str, offload: Offload, enabled: bool) -> io::Result<()> {
+    if interface.len() >= libc::IFNAMSIZ {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+    let mut value = EthtoolValue {
+        cmd: match offload {
+            Offload::Gro => ETHTOOL_SGRO,
+            Offload::Gso => ETHTOOL_SGSO,
+            Offload::Tso => ETHTOOL_STSO,
+        },
+        data: enabled as u32,
+    };
+    let mut req: IfReqData = unsafe { std::mem::zeroed() };
+    for (dst, &src) in req.name.iter_mut().zip(interface.as_bytes()) {
+        *dst = src as libc::c_char;
+    }
+    req.data = &mut value as *mut EthtoolValue as *mut libc::c_void;
+
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    if unsafe { libc::ioctl(socket.as_raw_fd(), SIOCETHTOOL, &mut req) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Adds the configured addresses to a link, skipping any it already has (from an earlier
+/// attempt, or a reload).
+async fn add_addresses(handle: &Handle, index: u32, ip_configs: &[IpConfig]) -> Result<(), Error> {
+    let existing = link_addresses(handle, index).await?;
+    for ip_config in ip_configs {
+        let ip_address = ip_config.address()?;
+        if existing.contains(&(ip_address, ip_config.mask)) {
+            info!("netlink: address {} already present on eth0", ip_address);
+            continue;
+        }
+        info!("netlink: adding IP address {} to eth0", ip_address);
+        let res = handle
+            .address()
+            .add(index, ip_address, ip_config.mask)
+            .execute()
+            .await;
+        match res {
+            // Present with a different prefix length, or added concurrently.
+            Err(rtnetlink::Error::NetlinkError(e)) if e.raw_code() == EEXIST => {
+                info!("netlink: address {} already present on eth0", ip_address);
+            }
+            res => res?,
+        }
+    }
+    Ok(())
+}
+
+/// The addresses (with prefix lengths) currently assigned to a link.
+async fn link_addresses(handle: &Handle, index: u32) -> Result<Vec<(IpAddr, u8)>, Error> {
+    let mut addresses = vec![];
+    let mut messages = handle
+        .address()
+        .get()
+        .set_link_index_filter(index)
+        .execute();
+    while let Some(msg) = messages.try_next().await? {
+        for attr in msg.attributes {
+            if let AddressAttribute::Address(addr) = attr {
+                addresses.push((addr, msg.header.prefix_len));
+            }
+        }
+    }
+    Ok(addresses)
+}
+
+/// Adds any missing addresses to eth0 on a fresh netlink connection. Used when config is
+/// reloaded on a running guest.
+pub async fn reapply_addresses(ip_configs: &[IpConfig]) -> Result<(), Error> {
+    if ops::dry_run() {
+        for ip_config in ip_configs {
+            info!(
+                "dry-run: add address {}/{} to eth0",
+                ip_config.address()?,
+                ip_config.mask
+            );
+        }
+        return Ok(());
+    }
+    let (connection, handle, _) = new_connection()?;
+    tokio::spawn(connection);
+    let eth0 = handle
+        .link()
+        .get()
+        .match_name("eth0".into())
+        .execute()
+        .try_next()
+        .await?
+        .ok_or_else(|| anyhow!("no eth0 link found"))?;
+    add_addresses(&handle, eth0.header.index, ip_configs).await
+}
+
+/// Re-installs the default routes on a fresh netlink connection, replacing the ones already
+/// there so a changed gateway takes effect. Used when config is reloaded on a running guest.
+pub async fn reapply_routes(ip_configs: &[IpConfig], network: &NetworkConfig) -> Result<(), Error> {
+    let gateways = default_gateways(ip_configs, network)?;
+    if ops::dry_run() {
+        log_default_routes(&gateways);
+        return Ok(());
+    }
+    let (connection, handle, _) = new_connection()?;
+    tokio::spawn(connection);
+    for gateway in gateways {
+        add_default_route(&handle, gateway, true).await?;
+    }
+    Ok(())
+}
+
+/// The default gateway for each address family: the configured one if given, otherwise the
+/// first address of that family with a gateway. A family with neither gets no default route.
+fn default_gateways(
+    ip_configs: &[IpConfig],
+    network: &NetworkConfig,
+) -> Result<Vec<IpAddr>, Error> {
+    let v4 = family_gateway(network.gateway_v4.as_deref(), ip_configs, false)?;
+    let v6 = family_gateway(network.gateway_v6.as_deref(), ip_configs, true)?;
+    Ok(v4.into_iter().chain(v6).collect())
+}
+
+fn family_gateway(
+    configured: Option<&str>,
+    ip_configs: &[IpConfig],
+    v6: bool,
+) -> Result<Option<IpAddr>, Error> {
+    if let Some(gateway) = configured {
+        let gateway: IpAddr = gateway
+            .parse()
+            .map_err(|e| anyhow!("invalid gateway {:?}: {}", gateway, e))?;
+        if gateway.is_ipv6() != v6 {
+            return Err(anyhow!(
+                "gateway {} is in the wrong address family",
+                gateway
+            ));
+        }
+        return Ok(Some(gateway));
+    }
+    for ip_config in ip_configs.iter().filter(|c| !c.gateway.is_empty()) {
+        let gateway = ip_config.gateway()?;
+        if gateway.is_ipv6() == v6 {
+            return Ok(Some(gateway));
+        }
+    }
+    Ok(None)
+}
+
+fn log_default_routes(gateways: &[IpAddr]) {
+    for gateway in gateways {
+        info!("dry-run: add default route via {}", gateway);
+    }
+}
+
+/// Adds a default route via `gateway`. With `replace`, an existing default route (through
+/// another gateway, say) is replaced rather than left as it is.
+async fn add_default_route(handle: &Handle, gateway: IpAddr, replace: bool) -> Result<(), Error> {
+    // The route add can race the interface coming up, so retry a few times before giving up.
+    let mut backoff = ROUTE_INITIAL_BACKOFF;
+    for attempt in 1..=ROUTE_ATTEMPTS {
+        info!("netlink: adding default route via gateway {}", gateway);
+        let res = match gateway {
+            IpAddr::V4(gw) => {
+                let req = handle.route().add().v4().gateway(gw);
+                if replace { req.replace() } else { req }.execute().await
+            }
+            IpAddr::V6(gw) => {
+                let req = handle.route().add().v6().gateway(gw);
+                if replace { req.replace() } else { req }.execute().await
+            }
+        };
+        match res {
+            Ok(()) => return Ok(()),
+            Err(rtnetlink::Error::NetlinkError(e)) if e.raw_code() == EEXIST => {
+                info!("netlink: default route via {} already exists", gateway);
+                return Ok(());
+            }
+            Err(e) if attempt < ROUTE_ATTEMPTS => {
+                warn!(
+                    "netlink: adding default route failed (attempt {}/{}): {}, retrying in {:?}",
+                    attempt, ROUTE_ATTEMPTS, e, backoff
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    unreachable!()
+}
+
+#[derive(Serialize, Debug)]
+struct NetworkState {
+    links: Vec<Link>,
+    routes: Vec<Route>,
+}
+
+#[derive(Serialize, Debug)]
+struct Link {
+    index: u32,
+    name: String,
+    mac: Option<String>,
+    mtu: Option<u32>,
+    /// Administratively up (`ip link set up`).
+    up: bool,
+    /// Operational state as reported by the driver, e.g. "up" or "down".
+    state: Option<String>,
+    addresses: Vec<Address>,
+}
+
+#[derive(Serialize, Debug)]
+struct Address {
+    address: IpAddr,
+    prefix_len: u8,
+    /// Remaining lifetime for addresses from DHCP or SLAAC; null for static ones.
+    valid_lifetime_secs: Option<u32>,
+}
+
+#[derive(Serialize, Debug)]
+struct Route {
+    /// "default", or the destination network as address/prefix.
+    destination: String,
+    gateway: Option<IpAddr>,
+    interface: Option<String>,
+    protocol: String,
+    scope: String,
+}
+
+// The kernel's IFA lifetime for addresses that never expire.
+const INFINITY_LIFE_TIME: u32 = u32::MAX;
+
+/// Reads the links, addresses and routes (main table only) the kernel currently has.
+async fn network_state() -> Result<NetworkState, Error> {
+    let (connection, handle, _) = new_connection()?;
+    tokio::spawn(connection);
+
+    let mut links = vec![];
+    let mut names = HashMap::new();
+    let mut link_messages = handle.link().get().execute();
+    while let Some(msg) = link_messages.try_next().await? {
+        let mut link = Link {
+            index: msg.header.index,
+            name: String::new(),
+            mac: None,
+            mtu: None,
+            up: msg.header.flags.contains(&LinkFlag::Up),
+            state: None,
+            addresses: vec![],
+        };
+        for attr in msg.attributes {
+            match attr {
+                LinkAttribute::IfName(name) => link.name = name,
+                LinkAttribute::Address(mac) => {
+                    link.mac = <[u8; 6]>::try_from(mac.as_slice())
+                        .ok()
+                        .map(|m| format_mac(&m))
+                }
+                LinkAttribute::Mtu(mtu) => link.mtu = Some(mtu),
+                LinkAttribute::OperState(state) => {
+                    link.state = Some(format!("{:?}", state).to_lowercase())
+                }
+                _ => {}
+            }
+        }
+        names.insert(link.index, link.name.clone());
+        links.push(link);
+    }
+
+    let mut address_messages = handle.address().get().execute();
+    while let Some(msg) = address_messages.try_next().await? {
+        let mut address = None;
+        let mut valid_lifetime_secs = None;
+        for attr in msg.attributes {
+            match attr {
+                // For point-to-point links Address is the peer and Local is ours; otherwise
+                // they're the same.
+                AddressAttribute::Local(addr) => address = Some(addr),
+                AddressAttribute::Address(addr) => {
+                    address.get_or_insert(addr);
+                }
+                AddressAttribute::CacheInfo(info) if info.ifa_valid != INFINITY_LIFE_TIME => {
+                    valid_lifetime_secs = Some(info.ifa_valid)
+                }
+                _ => {}
+            }
+        }
+        let link = links.iter_mut().find(|l| l.index == msg.header.index);
+        if let (Some(address), Some(link)) = (address, link) {
+            link.addresses.push(Address {
+                address,
+                prefix_len: msg.header.prefix_len,
+                valid_lifetime_secs,
+            });
+        }
+    }
+
+    let mut routes = vec![];
+    for version in [IpVersion::V4, IpVersion::V6] {
+        let mut route_messages = handle.route().get(version).execute();
+        while let Some(msg) = route_messages.try_next().await? {
+            if let Some(route) = route_from_message(msg, &names) {
+                routes.push(route);
+            }
+        }
+    }
+
+    Ok(NetworkState { links, routes })
+}
+
+fn route_from_message(msg: RouteMessage, names: &HashMap<u32, String>) -> Option<Route> {
+    let mut table = u32::from(msg.header.table);
+    let mut destination = None;
+    let mut gateway = None;
+    let mut interface = None;
+    for attr in msg.attributes {
+        match attr {
+            RouteAttribute::Table(t) => table = t,
+            RouteAttribute::Destination(addr) => destination = route_address(addr),
+            RouteAttribute::Gateway(addr) => gateway = route_address(addr),
+            RouteAttribute::Oif(index) => interface = names.get(&index).cloned(),
+            _ => {}
+        }
+    }
+    // The local and other tables are kernel bookkeeping rather than configuration.
+    if table != u32::from(RouteHeader::RT_TABLE_MAIN) {
+        return None;
+    }
+    let destination = match destination {
+        Some(addr) => format!("{}/{}", addr, msg.header.destination_prefix_length),
+        None => "default".to_string(),
+    };
+    Some(Route {
+        destination,
+        gateway,
+        interface,
+        protocol: msg.header.protocol.to_string().to_lowercase(),
+        scope: msg.header.scope.to_string().to_lowercase(),
+    })
+}
+
+fn route_address(addr: RouteAddress) -> Option<IpAddr> {
+    match addr {
+        RouteAddress::Inet(addr) => Some(addr.into()),
+        RouteAddress::Inet6(addr) => Some(addr.into()),
+        _ => None,
+    }
+}
+
+pub async fn show_network() -> Result<impl warp::Reply, warp::Rejection> {
+    let res = network_state().await;
+
+    info!(
+        "network: {:?}",
+        res.as_ref().map(|n| (n.links.len(), n.routes.len()))
+    );
+
+    Ok(match res {
+        Ok(n) => ApiReply::Ok(warp::reply::with_status(
+            warp::reply::json(&n),
+            StatusCode::OK,
+        )),
+        Err(e) => ApiReply::Err(warp::reply::with_status(
+            warp::reply::json(&ErrorMessage {
+                message: format!("{:#}", e),
+            }),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    })
+}
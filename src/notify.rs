@@ -0,0 +1,121 @@
+use super::config::{NotifyConfig, ReadyFileConfig, ReadyFileContents};
+use super::ops;
+use anyhow::Error;
+use log::{error, info};
+use serde::Serialize;
+use std::fs;
+use std::net::Shutdown;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio_vsock::{VsockAddr, VsockStream};
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What init tells the host once it is ready.
+#[derive(Serialize, Debug, Clone)]
+pub struct BootReport {
+    pub status: &'static str,
+    /// Whether networking came up; null when guest setup was skipped (not PID 1).
+    pub networking: Option<bool>,
+    pub entrypoint_started: bool,
+    /// Time since init started.
+    pub init_ms: u128,
+    /// Time since the kernel booted.
+    pub boot_ms: Option<u128>,
+}
+
+impl BootReport {
+    pub fn new(started: Instant, networking: Option<bool>, entrypoint_started: bool) -> Self {
+        BootReport {
+            status: "ready",
+            networking,
+            entrypoint_started,
+            init_ms: started.elapsed().as_millis(),
+            boot_ms: since_boot().map(|d| d.as_millis()),
+        }
+    }
+}
+
+fn since_boot() -> Option<Duration> {
+    let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+    if unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut ts) } != 0 {
+        return None;
+    }
+    Some(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+/// Sends the report to each configured target in the background. Failures are logged; the
+/// host falls back to polling `/v1/health`.
+pub fn send(config: NotifyConfig, report: BootReport) {
+    tokio::spawn(async move {
+        let mut line = match serde_json::to_vec(&report) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to encode boot report: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        if let Some(path) = &config.path {
+            match ops::write(path, &line) {
+                Ok(()) => info!("Wrote boot report to {}", path),
+                Err(e) => error!("Failed to write boot report to {}: {}", path, e),
+            }
+        }
+        if let Some(port) = config.vsock_port {
+            let addr = VsockAddr::new(config.vsock_cid, port);
+            match tokio::time::timeout(SEND_TIMEOUT, send_vsock(addr, &line)).await {
+                Ok(Ok(())) => info!("Sent boot report to vsock {}", addr),
+                Ok(Err(e)) => error!("Failed to send boot report to vsock {}: {}", addr, e),
+                Err(_) => error!("Sending boot report to vsock {} timed out", addr),
+            }
+        }
+    });
+}
+
+async fn send_vsock(addr: VsockAddr, line: &[u8]) -> Result<(), Error> {
+    if ops::dry_run() {
+        info!("dry-run: send {} bytes to vsock {}", line.len(), addr);
+        return Ok(());
+    }
+    let mut stream = VsockStream::connect(addr).await?;
+    stream.write_all(line).await?;
+    stream.shutdown(Shutdown::Write)?;
+    Ok(())
+}
+
+/// Creates the ready file. It's written under a temporary name and renamed into place, so
+/// anything that sees it also sees its contents.
+pub fn write_ready_file(config: &ReadyFileConfig, report: &BootReport) {
+    let contents = match config.contents {
+        ReadyFileContents::Empty => vec![],
+        ReadyFileContents::Timestamp => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            format!("{}\n", now.as_secs()).into_bytes()
+        }
+        ReadyFileContents::Report => match serde_json::to_vec(report) {
+            Ok(mut line) => {
+                line.push(b'\n');
+                line
+            }
+            Err(e) => {
+                error!("Failed to encode boot report: {}", e);
+                return;
+            }
+        },
+    };
+    let temp = format!("{}.tmp", config.path);
+    match ops::write(&temp, contents).and_then(|()| fs::rename(&temp, &config.path)) {
+        Ok(()) => info!("Wrote ready file {}", config.path),
+        Err(e) => error!("Failed to write ready file {}: {}", config.path, e),
+    }
+}
+
+pub fn remove_ready_file(config: &ReadyFileConfig) {
+    if let Err(e) = fs::remove_file(&config.path) {
+        error!("Failed to remove ready file {}: {}", config.path, e);
+    }
+}
@@ -0,0 +1,180 @@
+//! Side-effecting system operations used during guest setup. In dry-run mode each operation
+//! is logged instead of performed, so a run config can be reviewed on an ordinary machine.
+
+use log::info;
+use nix::mount::MsFlags;
+use nix::sys::stat::{Mode, SFlag};
+use std::fs::{set_permissions, OpenOptions, Permissions};
+use std::io::{self, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_dry_run(enabled: bool) {
+    DRY_RUN.store(enabled, Ordering::SeqCst);
+}
+
+pub fn dry_run() -> bool {
+    DRY_RUN.load(Ordering::SeqCst)
+}
+
+pub fn mount(
+    source: Option<&str>,
+    target: &str,
+    fstype: Option<&str>,
+    flags: MsFlags,
+    data: Option<&str>,
+) -> nix::Result<()> {
+    if dry_run() {
+        info!(
+            "dry-run: mount {:?} on {} (type {:?}, flags {:?}, data {:?})",
+            source, target, fstype, flags, data
+        );
+        return Ok(());
+    }
+    nix::mount::mount(source, target, fstype, flags, data)
+}
+
+pub fn umount(target: &str) -> nix::Result<()> {
+    if dry_run() {
+        info!("dry-run: umount {}", target);
+        return Ok(());
+    }
+    nix::mount::umount(target)
+}
+
+pub fn mkdir(path: &str, mode: Mode) -> nix::Result<()> {
+    if dry_run() {
+        info!("dry-run: mkdir {} (mode {:o})", path, mode.bits());
+        return Ok(());
+    }
+    nix::unistd::mkdir(path, mode)
+}
+
+pub fn create_dir_all(path: &Path) -> io::Result<()> {
+    if dry_run() {
+        info!("dry-run: mkdir -p {}", path.display());
+        return Ok(());
+    }
+    std::fs::create_dir_all(path)
+}
+
+pub fn mknod(path: &str, kind: SFlag, mode: Mode, major: u64, minor: u64) -> nix::Result<()> {
+    if dry_run() {
+        info!(
+            "dry-run: mknod {} {:?} {}:{} (mode {:o})",
+            path,
+            kind,
+            major,
+            minor,
+            mode.bits()
+        );
+        return Ok(());
+    }
+    nix::sys::stat::mknod(path, kind, mode, nix::sys::stat::makedev(major, minor))
+}
+
+pub fn chdir(path: &str) -> nix::Result<()> {
+    if dry_run() {
+        info!("dry-run: chdir {}", path);
+        return Ok(());
+    }
+    nix::unistd::chdir(path)
+}
+
+pub fn chroot(path: &str) -> nix::Result<()> {
+    if dry_run() {
+        info!("dry-run: chroot {}", path);
+        return Ok(());
+    }
+    nix::unistd::chroot(path)
+}
+
+pub fn symlink(target: &str, link: &str) -> nix::Result<()> {
+    if dry_run() {
+        info!("dry-run: symlink {} -> {}", link, target);
+        return Ok(());
+    }
+    nix::unistd::symlinkat(target, None, link)
+}
+
+pub fn write(path: &str, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let contents = contents.as_ref();
+    if dry_run() {
+        info!("dry-run: write {} bytes to {}", contents.len(), path);
+        return Ok(());
+    }
+    std::fs::write(path, contents)
+}
+
+pub fn append(path: &str, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let contents = contents.as_ref();
+    if dry_run() {
+        info!("dry-run: append {} bytes to {}", contents.len(), path);
+        return Ok(());
+    }
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)?
+        .write_all(contents)
+}
+
+pub fn remove_file(path: &str) -> io::Result<()> {
+    if dry_run() {
+        info!("dry-run: remove {}", path);
+        return Ok(());
+    }
+    std::fs::remove_file(path)
+}
+
+pub fn set_mode(path: &str, mode: u32) -> io::Result<()> {
+    if dry_run() {
+        info!("dry-run: chmod {:04o} {}", mode, path);
+        return Ok(());
+    }
+    set_permissions(path, Permissions::from_mode(mode))
+}
+
+pub fn sethostname(name: &str) -> nix::Result<()> {
+    if dry_run() {
+        info!("dry-run: sethostname {}", name);
+        return Ok(());
+    }
+    nix::unistd::sethostname(name)
+}
+
+pub fn setdomainname(name: &str) -> nix::Result<()> {
+    if dry_run() {
+        info!("dry-run: setdomainname {}", name);
+        return Ok(());
+    }
+    let res = unsafe { libc::setdomainname(name.as_ptr() as *const libc::c_char, name.len()) };
+    nix::errno::Errno::result(res).map(drop)
+}
+
+pub fn umask(mode: Mode) {
+    if dry_run() {
+        info!("dry-run: umask {:04o}", mode.bits());
+        return;
+    }
+    nix::sys::stat::umask(mode);
+}
+
+pub fn setrlimit(resource: rlimit::Resource, soft: u64, hard: u64) -> io::Result<()> {
+    if dry_run() {
+        info!("dry-run: setrlimit {:?} {} {}", resource, soft, hard);
+        return Ok(());
+    }
+    rlimit::setrlimit(resource, soft, hard)
+}
+
+pub fn reboot() -> nix::Result<()> {
+    if dry_run() {
+        info!("dry-run: reboot");
+        return Ok(());
+    }
+    nix::sys::reboot::reboot(nix::sys::reboot::RebootMode::RB_AUTOBOOT).map(drop)
+}
@@ -0,0 +1,94 @@
+//! ICMP echo, for checking that the gateways answer once networking is up.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::time::{Duration, Instant};
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+/// Sends one echo request to `addr` and waits up to `timeout` for the reply, returning the
+/// round trip time. Needs CAP_NET_RAW, which init has as PID 1.
+pub fn echo(addr: IpAddr, seq: u16, timeout: Duration) -> io::Result<Duration> {
+    let (family, protocol, request, reply) = match addr {
+        IpAddr::V4(_) => (
+            libc::AF_INET,
+            libc::IPPROTO_ICMP,
+            ICMP_ECHO_REQUEST,
+            ICMP_ECHO_REPLY,
+        ),
+        IpAddr::V6(_) => (
+            libc::AF_INET6,
+            libc::IPPROTO_ICMPV6,
+            ICMPV6_ECHO_REQUEST,
+            ICMPV6_ECHO_REPLY,
+        ),
+    };
+    let fd = unsafe { libc::socket(family, libc::SOCK_RAW | libc::SOCK_CLOEXEC, protocol) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // A std UDP socket is a thin wrapper over sendto and recvfrom, which is all a raw socket
+    // needs.
+    let socket = UdpSocket::from(unsafe { OwnedFd::from_raw_fd(fd) });
+
+    let id = std::process::id() as u16;
+    let mut packet = [0u8; 16];
+    packet[0] = request;
+    packet[4..6].copy_from_slice(&id.to_be_bytes());
+    packet[6..8].copy_from_slice(&seq.to_be_bytes());
+    packet[8..].copy_from_slice(b"init-gw\0");
+    // The kernel fills in ICMPv6 checksums itself.
+    if addr.is_ipv4() {
+        let sum = checksum(&packet);
+        packet[2..4].copy_from_slice(&sum.to_be_bytes());
+    }
+
+    let sent = Instant::now();
+    socket.send_to(&packet, SocketAddr::new(addr, 0))?;
+    let mut buf = [0u8; 1500];
+    loop {
+        let remaining = timeout
+            .checked_sub(sent.elapsed())
+            .filter(|d| !d.is_zero())
+            .ok_or(io::ErrorKind::TimedOut)?;
+        socket.set_read_timeout(Some(remaining))?;
+        let (n, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                return Err(io::ErrorKind::TimedOut.into())
+            }
+            Err(e) => return Err(e),
+        };
+        if from.ip() != addr {
+            continue;
+        }
+        // Raw IPv4 sockets get the IP header as well; IPv6 ones only the ICMP message.
+        let icmp = match addr {
+            IpAddr::V4(_) => &buf[((buf[0] & 0x0f) as usize * 4).min(n)..n],
+            IpAddr::V6(_) => &buf[..n],
+        };
+        if icmp.len() >= 8
+            && icmp[0] == reply
+            && icmp[4..6] == id.to_be_bytes()
+            && icmp[6..8] == seq.to_be_bytes()
+        {
+            return Ok(sent.elapsed());
+        }
+    }
+}
+
+/// The Internet checksum (RFC 1071).
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
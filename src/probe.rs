@@ -0,0 +1,89 @@
+use super::config::ProbeConfig;
+use super::process;
+use super::reaper;
+use anyhow::{anyhow, Error};
+use log::{debug, info};
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+
+/// Runs the readiness probe for `name` in the background until it has passed
+/// `success_threshold` times in a row. `set_ready` is called with false straight away and
+/// with true once the probe passes. `env` is the probed process's environment.
+pub fn start(
+    probe: ProbeConfig,
+    env: HashMap<String, String>,
+    name: String,
+    set_ready: impl Fn(bool) + Send + 'static,
+) -> Result<JoinHandle<()>, Error> {
+    if probe.command.is_some() == probe.tcp.is_some() {
+        return Err(anyhow!(
+            "readiness_probe needs exactly one of command and tcp"
+        ));
+    }
+    set_ready(false);
+
+    let interval = Duration::from_millis(probe.interval_ms);
+    let timeout = Duration::from_millis(probe.timeout_ms);
+    Ok(tokio::spawn(async move {
+        let mut passes = 0;
+        loop {
+            match tokio::time::timeout(timeout, check(&probe, &env)).await {
+                Ok(Ok(())) => passes += 1,
+                Ok(Err(e)) => {
+                    debug!("Readiness probe for {} failed: {:#}", name, e);
+                    passes = 0;
+                }
+                Err(_) => {
+                    debug!("Readiness probe for {} timed out after {:?}", name, timeout);
+                    passes = 0;
+                }
+            }
+            if passes >= probe.success_threshold {
+                info!("Readiness probe passed, {} is ready", name);
+                set_ready(true);
+                return;
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }))
+}
+
+async fn check(probe: &ProbeConfig, env: &HashMap<String, String>) -> Result<(), Error> {
+    if let Some(addr) = &probe.tcp {
+        TcpStream::connect(addr).await?;
+        return Ok(());
+    }
+
+    let argv = probe.command.as_deref().unwrap_or_default();
+    let name = argv
+        .first()
+        .ok_or_else(|| anyhow!("readiness_probe.command is empty"))?;
+    let program =
+        process::resolve_program(name, env.get("PATH").map(String::as_str)).map_err(Error::msg)?;
+    let mut cmd = Command::new(program);
+    cmd.arg0(name)
+        .args(&argv[1..])
+        .envs(env)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        // A probe that times out is dropped mid-wait; make sure it doesn't linger.
+        .kill_on_drop(true);
+    let (child, exited) = reaper::spawn(&mut cmd)?;
+    let exit = exited
+        .await
+        .map_err(|_| anyhow!("lost track of the probe's exit status"))?;
+    drop(child);
+    match exit.code {
+        Some(0) => Ok(()),
+        _ => Err(anyhow!(
+            "probe exited (code {:?}, signal {:?})",
+            exit.code,
+            exit.signal
+        )),
+    }
+}
@@ -0,0 +1,346 @@
+use super::ops;
+use super::reaper::{self, Exit, Rusage};
+use anyhow::{anyhow, Error};
+use log::{info, warn};
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::pty::{grantpt, posix_openpt, ptsname_r, unlockpt};
+use nix::sched::{sched_getaffinity, sched_setaffinity, CpuSet};
+use nix::sys::termios::{tcgetattr, tcsetattr, OutputFlags, SetArg};
+use nix::unistd::{setgid, setgroups, setpgid, setsid, setuid, Gid, Pid, Uid};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::fd::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+
+pub const NICE_RANGE: std::ops::RangeInclusive<i32> = -20..=19;
+
+/// PATH for processes init starts, unless the run config says otherwise.
+pub const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Per-child settings applied between fork and exec. Everything that needs to happen in the
+/// child goes through the single `pre_exec` hook installed by `apply`, so the order of the
+/// steps is explicit: the session and terminal are set up and the niceness is set while
+/// still root, then privileges are dropped.
+#[derive(Debug, Clone, Default)]
+pub struct ChildOptions {
+    /// Start a new session (and process group) with the child as its leader.
+    pub new_session: bool,
+    /// Put the child in a new process group of its own, so it can be signalled together
+    /// with anything it starts. Implied by `new_session`.
+    pub new_process_group: bool,
+    /// Terminal to make the child's controlling terminal and stdio. Implies `new_session`.
+    pub controlling_tty: Option<CString>,
+    pub nice: Option<i32>,
+    pub user: Option<(Uid, Gid)>,
+    /// CPUs to pin the child to, from `cpu_affinity`.
+    pub cpu_affinity: Option<CpuSet>,
+    /// Open descriptors to hand to the child as fds 3, 4, ... in this order. Init's own
+    /// copies can be closed once the child has started.
+    pub pass_fds: Vec<RawFd>,
+    /// Resource limits to set on the child only, from `rlimits`.
+    pub rlimits: Vec<(Rlimit, libc::rlimit)>,
+}
+
+/// A resource `setrlimit` can limit, by its name without `RLIMIT_`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Rlimit {
+    /// Address space, in bytes.
+    As,
+    Core,
+    /// CPU time, in seconds.
+    Cpu,
+    Data,
+    Fsize,
+    Memlock,
+    Nofile,
+    Nproc,
+    Stack,
+}
+
+impl Rlimit {
+    fn resource(self) -> i32 {
+        (match self {
+            Rlimit::As => libc::RLIMIT_AS,
+            Rlimit::Core => libc::RLIMIT_CORE,
+            Rlimit::Cpu => libc::RLIMIT_CPU,
+            Rlimit::Data => libc::RLIMIT_DATA,
+            Rlimit::Fsize => libc::RLIMIT_FSIZE,
+            Rlimit::Memlock => libc::RLIMIT_MEMLOCK,
+            Rlimit::Nofile => libc::RLIMIT_NOFILE,
+            Rlimit::Nproc => libc::RLIMIT_NPROC,
+            Rlimit::Stack => libc::RLIMIT_STACK,
+        }) as i32
+    }
+}
+
+/// A limit as given in a request: one number for both the soft and hard limit, or the two
+/// separately.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(untagged)]
+pub enum RlimitValue {
+    Both(u64),
+    Split { soft: u64, hard: u64 },
+}
+
+/// Checks `limits` and turns them into `ChildOptions::rlimits`.
+pub fn rlimits(
+    limits: &BTreeMap<Rlimit, RlimitValue>,
+) -> Result<Vec<(Rlimit, libc::rlimit)>, String> {
+    limits
+        .iter()
+        .map(|(&resource, &value)| {
+            let (soft, hard) = match value {
+                RlimitValue::Both(limit) => (limit, limit),
+                RlimitValue::Split { soft, hard } => (soft, hard),
+            };
+            if soft > hard {
+                return Err(format!(
+                    "rlimit {:?}: soft limit {} is above the hard limit {}",
+                    resource, soft, hard
+                ));
+            }
+            let limit = libc::rlimit {
+                rlim_cur: soft as libc::rlim_t,
+                rlim_max: hard as libc::rlim_t,
+            };
+            Ok((resource, limit))
+        })
+        .collect()
+}
+
+/// The first descriptor `ChildOptions::pass_fds` are numbered from, after stdio.
+pub const FIRST_PASSED_FD: RawFd = 3;
+
+pub fn validate_nice(nice: i32) -> Result<(), String> {
+    if NICE_RANGE.contains(&nice) {
+        Ok(())
+    } else {
+        Err(format!(
+            "nice must be between {} and {}, got {}",
+            NICE_RANGE.start(),
+            NICE_RANGE.end(),
+            nice
+        ))
+    }
+}
+
+/// The CPUs in `cpus` that init can run on, as a set for `ChildOptions::cpu_affinity`. Any
+/// others are logged and left out; it's an error if that leaves none.
+pub fn cpu_affinity(cpus: &BTreeSet<usize>) -> Result<CpuSet, String> {
+    let available = sched_getaffinity(Pid::from_raw(0)).map_err(|e| e.to_string())?;
+    let is_available = |cpu: usize| available.is_set(cpu).unwrap_or(false);
+    let mut set = CpuSet::new();
+    for &cpu in cpus {
+        if !is_available(cpu) {
+            let all: Vec<usize> = (0..CpuSet::count()).filter(|&c| is_available(c)).collect();
+            warn!(
+                "Ignoring CPU {} in cpu_affinity: only CPUs {:?} are available",
+                cpu, all
+            );
+            continue;
+        }
+        set.set(cpu).map_err(|e| e.to_string())?;
+    }
+    if !cpus.iter().any(|&cpu| is_available(cpu)) {
+        return Err(format!(
+            "none of the CPUs in cpu_affinity ({:?}) are available",
+            cpus
+        ));
+    }
+    Ok(set)
+}
+
+pub fn apply(cmd: &mut Command, opts: &ChildOptions) {
+    let opts = opts.clone();
+    // Allocated here because the hook can't.
+    let mut moved = vec![0; opts.pass_fds.len()];
+    // Only async-signal-safe calls may be made in here: no allocation, no logging.
+    unsafe {
+        cmd.pre_exec(move || {
+            if opts.new_session || opts.controlling_tty.is_some() {
+                setsid()?;
+            } else if opts.new_process_group {
+                setpgid(Pid::from_raw(0), Pid::from_raw(0))?;
+            }
+            if let Some(tty) = &opts.controlling_tty {
+                let fd = Errno::result(libc::open(tty.as_ptr(), libc::O_RDWR))?;
+                Errno::result(libc::ioctl(fd, libc::TIOCSCTTY, 0))?;
+                for stdio in 0..=2 {
+                    Errno::result(libc::dup2(fd, stdio))?;
+                }
+                if fd > 2 {
+                    libc::close(fd);
+                }
+            }
+            // The passed fds are first moved clear of the range they're going to, so that
+            // placing one can't clobber another that's still to be placed. dup2 leaves the
+            // placed copies without FD_CLOEXEC, unlike the originals and the moved ones.
+            let end = FIRST_PASSED_FD + opts.pass_fds.len() as RawFd;
+            for (fd, slot) in opts.pass_fds.iter().zip(moved.iter_mut()) {
+                *slot = Errno::result(libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, end))?;
+            }
+            for (i, fd) in moved.iter().enumerate() {
+                Errno::result(libc::dup2(*fd, FIRST_PASSED_FD + i as RawFd))?;
+            }
+            if let Some(nice) = opts.nice {
+                Errno::result(libc::setpriority(libc::PRIO_PROCESS as _, 0, nice))?;
+            }
+            if let Some(cpus) = &opts.cpu_affinity {
+                sched_setaffinity(Pid::from_raw(0), cpus)?;
+            }
+            // Set while still root, which raising a hard limit needs.
+            for (resource, limit) in &opts.rlimits {
+                Errno::result(libc::setrlimit(resource.resource() as _, limit))?;
+            }
+            if let Some((uid, gid)) = opts.user {
+                setgroups(&[gid])?;
+                setgid(gid)?;
+                setuid(uid)?;
+            }
+            Ok(())
+        });
+    }
+}
+
+/// A pseudo-terminal for a child to run on. Init keeps the terminal side open as well, so
+/// that reading the master doesn't hit end-of-file before the child has opened it; drop
+/// `terminal` once the child has exited and the master reads to the end.
+pub struct Pty {
+    pub master: File,
+    pub terminal: File,
+    /// Path of the terminal side, for `ChildOptions::controlling_tty`.
+    pub path: CString,
+}
+
+pub fn open_pty() -> Result<Pty, Error> {
+    let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC)?;
+    grantpt(&master)?;
+    unlockpt(&master)?;
+    let path = ptsname_r(&master)?;
+    let terminal = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY | libc::O_CLOEXEC)
+        .open(&path)?;
+
+    // Keep output as the program wrote it, without "\n" turned into "\r\n".
+    let mut attrs = tcgetattr(&terminal)?;
+    attrs.output_flags.remove(OutputFlags::ONLCR);
+    tcsetattr(&terminal, SetArg::TCSANOW, &attrs)?;
+
+    let master = unsafe { File::from_raw_fd(master.into_raw_fd()) };
+    Ok(Pty {
+        master,
+        terminal,
+        path: CString::new(path)?,
+    })
+}
+
+/// Makes spawn errors caused by `ChildOptions` readable, since the child can't log them.
+pub fn describe_spawn_error(e: &io::Error, opts: &ChildOptions) -> String {
+    match (e.raw_os_error(), opts.nice) {
+        (Some(libc::EACCES) | Some(libc::EPERM), Some(nice)) if nice < 0 => format!(
+            "{} (setting a negative niceness ({}) requires CAP_SYS_NICE)",
+            e, nice
+        ),
+        _ => e.to_string(),
+    }
+}
+
+/// Finds the executable a bare command name refers to by searching `path` (init's own `PATH`
+/// if not given, or the default if init has none). Names containing a slash are used as they
+/// are.
+pub fn resolve_program(name: &str, path: Option<&str>) -> Result<PathBuf, String> {
+    if name.contains('/') {
+        return Ok(PathBuf::from(name));
+    }
+    let path = path
+        .map(String::from)
+        .or_else(|| env::var("PATH").ok())
+        .unwrap_or_else(|| DEFAULT_PATH.to_string());
+    let dirs: Vec<&str> = path.split(':').filter(|d| !d.is_empty()).collect();
+    for dir in &dirs {
+        let candidate = Path::new(dir).join(name);
+        let executable = candidate
+            .metadata()
+            .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false);
+        if executable {
+            return Ok(candidate);
+        }
+    }
+    Err(format!(
+        "{}: binary not found in PATH (searched {})",
+        name,
+        if dirs.is_empty() {
+            "nothing, PATH is empty".to_string()
+        } else {
+            dirs.join(", ")
+        }
+    ))
+}
+
+/// A command for one of init's own helpers (fsck, modprobe and the like). Helpers get a fixed,
+/// minimal environment rather than init's, which may be nearly empty when started by the
+/// kernel, or the workload's, which the image controls.
+pub fn helper_command(program: &str) -> Command {
+    Command::from(blocking_helper_command(program))
+}
+
+/// `helper_command` for the few helpers run before the runtime and the reaper are up (such
+/// as extracting the root image), which are waited on directly.
+pub fn blocking_helper_command(program: &str) -> std::process::Command {
+    let mut cmd = std::process::Command::new(program);
+    cmd.env_clear()
+        .env("PATH", DEFAULT_PATH)
+        .env("LC_ALL", "C")
+        .stdin(Stdio::null());
+    cmd
+}
+
+/// Runs a helper to completion through the reaper and returns its exit along with its combined
+/// output, for logging. In dry-run mode the helper is only logged, and reported as succeeding.
+pub async fn run_helper(program: &str, args: &[&str]) -> Result<(Exit, String), Error> {
+    if ops::dry_run() {
+        info!("dry-run: run {} {}", program, args.join(" "));
+        let exit = Exit {
+            code: Some(0),
+            signal: None,
+            rusage: Rusage::default(),
+        };
+        return Ok((exit, String::new()));
+    }
+    info!("Running {} {}", program, args.join(" "));
+    let mut cmd = helper_command(program);
+    cmd.args(args).stdout(Stdio::piped()).stderr(Stdio::piped());
+    let (mut child, exited) =
+        reaper::spawn(&mut cmd).map_err(|e| anyhow!("running {}: {}", program, e))?;
+
+    let mut output = vec![];
+    let mut stderr = vec![];
+    let (mut child_out, mut child_err) =
+        (child.stdout.take().unwrap(), child.stderr.take().unwrap());
+    let (out_res, err_res) = tokio::join!(
+        child_out.read_to_end(&mut output),
+        child_err.read_to_end(&mut stderr)
+    );
+    out_res?;
+    err_res?;
+    output.extend(stderr);
+
+    let exit = exited
+        .await
+        .map_err(|_| anyhow!("lost track of {}'s exit status", program))?;
+    drop(child);
+    Ok((exit, String::from_utf8_lossy(&output).into_owned()))
+}
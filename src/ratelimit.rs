@@ -0,0 +1,33 @@
+use super::config::RateLimitConfig;
+use std::time::Instant;
+
+/// A token bucket. The limits are passed in on every call rather than stored, so a reloaded
+/// config takes effect on the next request.
+#[derive(Debug)]
+pub struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: &RateLimitConfig) -> TokenBucket {
+        TokenBucket {
+            tokens: limit.burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes a token if one is available.
+    pub fn try_take(&mut self, limit: &RateLimitConfig) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
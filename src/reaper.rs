@@ -0,0 +1,254 @@
+use super::events::{self, Event};
+use log::{info, log, warn, LevelFilter};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::process::{Child, Command};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::oneshot;
+
+/// How a child exited, along with the resources it used.
+#[derive(Debug, Clone)]
+pub struct Exit {
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+    pub rusage: Rusage,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Rusage {
+    pub user_cpu_seconds: f64,
+    pub system_cpu_seconds: f64,
+    pub max_rss_kb: i64,
+}
+
+impl From<&libc::rusage> for Rusage {
+    fn from(ru: &libc::rusage) -> Rusage {
+        let seconds = |tv: libc::timeval| tv.tv_sec as f64 + tv.tv_usec as f64 / 1_000_000.0;
+        Rusage {
+            user_cpu_seconds: seconds(ru.ru_utime),
+            system_cpu_seconds: seconds(ru.ru_stime),
+            max_rss_kb: ru.ru_maxrss,
+        }
+    }
+}
+
+// Every child reaped, and the orphans among them (the ones nobody was waiting on).
+static REAPED: AtomicU64 = AtomicU64::new(0);
+static ORPHANS_REAPED: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ReapCounts {
+    pub reaped: u64,
+    pub orphans: u64,
+}
+
+pub fn counts() -> ReapCounts {
+    ReapCounts {
+        reaped: REAPED.load(Ordering::Relaxed),
+        orphans: ORPHANS_REAPED.load(Ordering::Relaxed),
+    }
+}
+
+// Children whose exit someone is waiting on, by pid.
+fn waiters() -> &'static Mutex<HashMap<i32, oneshot::Sender<Exit>>> {
+    static WAITERS: OnceLock<Mutex<HashMap<i32, oneshot::Sender<Exit>>>> = OnceLock::new();
+    WAITERS.get_or_init(Default::default)
+}
+
+/// Spawns `cmd` and registers it with the reaper, returning the child (for its stdio) and a
+/// receiver for its exit. Init reaps every child itself, so callers must wait on the
+/// receiver rather than on the `Child`, and keep the `Child` alive until then (dropping it
+/// early hands the pid to tokio's own orphan reaping).
+pub fn spawn(cmd: &mut Command) -> io::Result<(Child, oneshot::Receiver<Exit>)> {
+    // Hold the lock across the spawn so the reaper can't collect the child before its
+    // waiter is registered.
+    let mut waiters = waiters().lock().unwrap();
+    let child = cmd.spawn()?;
+    let (tx, rx) = oneshot::channel();
+    if let Some(pid) = child.id() {
+        waiters.insert(pid as i32, tx);
+    }
+    Ok((child, rx))
+}
+
+/// Starts the background task that reaps every exited child (including orphans reparented
+/// to init) and reports exits of registered children to their waiters. Orphans are logged
+/// at `log_level`. With a `stuck_after`, registered children are also checked for being
+/// stuck; see `check_stuck`.
+pub fn start(log_level: LevelFilter, stuck_after: Option<Duration>) {
+    let mut sigchld = signal(SignalKind::child()).expect("Failed to create signal handler");
+    tokio::spawn(async move {
+        // SIGCHLDs that arrive together are delivered as one, so each wakeup drains every
+        // zombie there is. The sweep catches any exit that slipped between a drain finishing
+        // and the next wait starting.
+        let mut sweep = tokio::time::interval(REAP_SWEEP_INTERVAL);
+        loop {
+            reap(log_level);
+            tokio::select! {
+                received = sigchld.recv() => {
+                    if received.is_none() {
+                        warn!("SIGCHLD handler closed, reaping on the sweep interval only");
+                        break;
+                    }
+                }
+                _ = sweep.tick() => {}
+            }
+        }
+        loop {
+            sweep.tick().await;
+            reap(log_level);
+        }
+    });
+    if let Some(after) = stuck_after {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(STUCK_CHECK_INTERVAL.min(after));
+            loop {
+                interval.tick().await;
+                check_stuck(after);
+            }
+        });
+    }
+}
+
+const REAP_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Makes init a child subreaper (`PR_SET_CHILD_SUBREAPER`), so descendants orphaned by
+/// their parents are reparented to it instead of to the system's PID 1. Only needed when
+/// init isn't PID 1 itself, which gets every orphan anyway.
+pub fn set_subreaper() -> io::Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+const STUCK_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A registered child that has been in uninterruptible sleep (`D`) or a zombie (`Z`) for
+/// longer than the configured threshold.
+#[derive(Debug, Clone, Serialize)]
+pub struct StuckProcess {
+    pub pid: i32,
+    pub command: String,
+    pub state: char,
+    pub stuck_for_ms: u128,
+}
+
+struct Stuck {
+    command: String,
+    state: char,
+    since: Instant,
+    reported: bool,
+}
+
+// Registered children seen in D or Z state, by pid, until they leave it or are reaped.
+fn stuck() -> &'static Mutex<BTreeMap<i32, Stuck>> {
+    static STUCK: OnceLock<Mutex<BTreeMap<i32, Stuck>>> = OnceLock::new();
+    STUCK.get_or_init(Default::default)
+}
+
+/// The children that have been stuck past the threshold, for `/v1/health`.
+pub fn stuck_processes() -> Vec<StuckProcess> {
+    stuck()
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, stuck)| stuck.reported)
+        .map(|(pid, stuck)| StuckProcess {
+            pid: *pid,
+            command: stuck.command.clone(),
+            state: stuck.state,
+            stuck_for_ms: stuck.since.elapsed().as_millis(),
+        })
+        .collect()
+}
+
+/// Looks at every child still waiting to be reaped. One blocked in the kernel (`D`) can't be
+/// killed, and a zombie that outlives a SIGCHLD means reaping went wrong; either way init
+/// would otherwise never notice, so a child that stays that way past `after` is logged and
+/// sent as an event, once.
+fn check_stuck(after: Duration) {
+    let pids: Vec<i32> = waiters().lock().unwrap().keys().copied().collect();
+    let mut stuck = stuck().lock().unwrap();
+    stuck.retain(|pid, _| pids.contains(pid));
+    for pid in pids {
+        match proc_stat(pid) {
+            Some((command, state @ ('D' | 'Z'))) => {
+                let entry = stuck.entry(pid).or_insert_with(|| Stuck {
+                    command,
+                    state,
+                    since: Instant::now(),
+                    reported: false,
+                });
+                entry.state = state;
+                if !entry.reported && entry.since.elapsed() >= after {
+                    entry.reported = true;
+                    warn!(
+                        "PID {} ({}) has been in state {} for {:?}",
+                        pid,
+                        entry.command,
+                        state,
+                        entry.since.elapsed()
+                    );
+                    events::emit(Event::Stuck {
+                        pid,
+                        command: entry.command.clone(),
+                        state,
+                    });
+                }
+            }
+            _ => {
+                if stuck.remove(&pid).is_some_and(|s| s.reported) {
+                    info!("PID {} is no longer stuck", pid);
+                }
+            }
+        }
+    }
+}
+
+/// A process's command name and state letter, from `/proc/<pid>/stat`.
+fn proc_stat(pid: i32) -> Option<(String, char)> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command is in parentheses and may itself contain ") ".
+    let (head, rest) = stat.rsplit_once(')')?;
+    let command = head.split_once('(')?.1.to_string();
+    let state = rest.trim_start().chars().next()?;
+    Some((command, state))
+}
+
+fn reap(log_level: LevelFilter) {
+    loop {
+        let mut status = 0;
+        let mut ru: libc::rusage = unsafe { std::mem::zeroed() };
+        let pid = unsafe { libc::wait4(-1, &mut status, libc::WNOHANG, &mut ru) };
+        if pid < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+            continue;
+        }
+        // 0: children are left but none has exited; -1 (ECHILD): there are none.
+        if pid <= 0 {
+            return;
+        }
+
+        REAPED.fetch_add(1, Ordering::Relaxed);
+        let exit = Exit {
+            code: libc::WIFEXITED(status).then(|| libc::WEXITSTATUS(status)),
+            signal: libc::WIFSIGNALED(status).then(|| libc::WTERMSIG(status)),
+            rusage: Rusage::from(&ru),
+        };
+        match waiters().lock().unwrap().remove(&pid) {
+            Some(tx) => {
+                tx.send(exit).ok();
+            }
+            None => {
+                ORPHANS_REAPED.fetch_add(1, Ordering::Relaxed);
+                if let Some(level) = log_level.to_level() {
+                    log!(level, "Reaped zombie process with PID: {}", pid);
+                }
+            }
+        }
+    }
+}
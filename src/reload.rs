@@ -0,0 +1,238 @@
+use super::cmdline::KernelCmdline;
+use super::config::{self, RunConfig};
+use super::events::{self, Event};
+use super::state::SharedState;
+use super::{etc, files, is_pid1, net, server, sysctl, ApiReply, ErrorMessage};
+use anyhow::{Context, Error};
+use log::{error, info};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use serde::Serialize;
+use serde_json::Value;
+use std::path::Path;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+
+#[derive(Serialize)]
+struct ReloadResponse {
+    reapplied: Vec<&'static str>,
+}
+
+/// Re-applies the sections of the run config that are safe to apply more than once, and moves
+/// the control API if its listener changed. Mounts and the root pivot are one-shot boot steps
+/// and are deliberately left alone.
+async fn reload(
+    state: &SharedState,
+    mut run_config: RunConfig,
+) -> Result<Vec<&'static str>, Error> {
+    KernelCmdline::read().apply(&mut run_config);
+    // The stored config has the include merged in, so the new one needs it too to compare.
+    if is_pid1() {
+        run_config.apply_include().context("include")?;
+    }
+    info!("Reloading run configuration: {:?}", run_config.redacted());
+    info!(
+        "reload: changed settings: {:?}",
+        changed_settings(&state.config(), &run_config)
+    );
+
+    let mut reapplied = vec![];
+
+    // The control API runs whether or not init is PID 1.
+    run_config.server.apply_env()?;
+    // Settings checked per request (auth token, rate limit) only need the config updated.
+    let server_changed = state.config().server != run_config.server;
+    server::restart(&run_config.server, state.clone()).context("server")?;
+    if server_changed {
+        state.update_config(|config| config.server = run_config.server.clone());
+        reapplied.push("server");
+    }
+
+    if !is_pid1() {
+        info!("Not running as PID 1, not reapplying guest configuration");
+        return Ok(reapplied);
+    }
+
+    let reloadable: Vec<_> = run_config
+        .files
+        .iter()
+        .filter(|file| file.reloadable)
+        .cloned()
+        .collect();
+    if !reloadable.is_empty() {
+        files::write_files(
+            &reloadable,
+            run_config.files_root.as_deref(),
+            run_config.transactional_files,
+        )
+        .context("files")?;
+        reapplied.push("files");
+    }
+
+    files::create_symlinks(&run_config.symlinks).context("symlinks")?;
+    reapplied.push("symlinks");
+
+    etc::write_resolv_conf(run_config.etc_resolv.as_ref(), run_config.hosts_only_dns)
+        .context("resolv.conf")?;
+    reapplied.push("resolv.conf");
+
+    etc::write_hosts(run_config.etc_hosts.as_deref()).context("hosts")?;
+    reapplied.push("hosts");
+
+    etc::write_nsswitch_conf(run_config.nsswitch.as_ref(), run_config.hosts_only_dns)
+        .context("nsswitch.conf")?;
+    reapplied.push("nsswitch.conf");
+
+    sysctl::apply(&run_config.sysctls);
+    reapplied.push("sysctls");
+
+    if let Some(ip_configs) = &run_config.ip_configs {
+        net::reapply_addresses(ip_configs)
+            .await
+            .context("addresses")?;
+        reapplied.push("addresses");
+        net::reapply_routes(ip_configs, &run_config.network)
+            .await
+            .context("routes")?;
+        reapplied.push("routes");
+    }
+
+    state.update_config(|config| {
+        config.files = run_config.files;
+        config.files_root = run_config.files_root;
+        config.transactional_files = run_config.transactional_files;
+        config.symlinks = run_config.symlinks;
+        config.etc_resolv = run_config.etc_resolv;
+        config.etc_hosts = run_config.etc_hosts;
+        config.nsswitch = run_config.nsswitch;
+        config.hosts_only_dns = run_config.hosts_only_dns;
+        config.sysctls = run_config.sysctls;
+        config.ip_configs = run_config.ip_configs;
+        config.network = run_config.network;
+    });
+
+    Ok(reapplied)
+}
+
+/// The top-level run config keys whose values differ between `old` and `new`.
+fn changed_settings(old: &RunConfig, new: &RunConfig) -> Vec<String> {
+    let (Ok(Value::Object(old)), Ok(Value::Object(new))) =
+        (serde_json::to_value(old), serde_json::to_value(new))
+    else {
+        return vec![];
+    };
+    new.into_iter()
+        .filter(|(key, value)| old.get(key) != Some(value))
+        .map(|(key, _)| key)
+        .collect()
+}
+
+async fn reload_from_body(state: &SharedState, body: &[u8]) -> Result<Vec<&'static str>, Error> {
+    let run_config = if body.is_empty() {
+        load_boot_config()?
+    } else {
+        RunConfig::from_slice(body)?
+    };
+    reload(state, run_config).await
+}
+
+/// The run config from wherever init read it at boot.
+fn load_boot_config() -> Result<RunConfig, Error> {
+    let path = KernelCmdline::read().config_path;
+    RunConfig::load(&config::run_config_path(path.as_deref()))
+}
+
+/// Reloads the run config from where it was read at boot whenever init gets a SIGHUP.
+pub fn reload_on_sighup(state: SharedState) -> Result<(), Error> {
+    let mut sighup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while sighup.recv().await.is_some() {
+            events::emit(Event::Signal { signal: "SIGHUP" });
+            info!("Received SIGHUP, reloading");
+            let res = match load_boot_config() {
+                Ok(run_config) => reload(&state, run_config).await,
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(reapplied) => info!("reload: reapplied {:?}", reapplied),
+                Err(e) => error!("reload on SIGHUP failed: {:#}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+pub async fn handle_reload(
+    state: SharedState,
+    body: Bytes,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let res = reload_from_body(&state, &body).await;
+
+    info!("reload: {:?}", res);
+
+    Ok(match res {
+        Ok(reapplied) => ApiReply::Ok(warp::reply::with_status(
+            warp::reply::json(&ReloadResponse { reapplied }),
+            StatusCode::OK,
+        )),
+        Err(e) => ApiReply::Err(warp::reply::with_status(
+            warp::reply::json(&ErrorMessage {
+                message: format!("{:#}", e),
+            }),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    })
+}
+
+/// Watches `path` with inotify and reloads from it whenever it is written or replaced.
+/// The parent directory is watched rather than the file itself so that editors and tools
+/// which replace the file via rename are picked up too.
+pub fn watch(path: String, state: SharedState) -> Result<(), Error> {
+    let target = Path::new(&path);
+    let dir = target.parent().unwrap_or(Path::new("/")).to_path_buf();
+    let name = target
+        .file_name()
+        .context("watched config path has no file name")?
+        .to_os_string();
+
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC)?;
+    inotify.add_watch(
+        &dir,
+        AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO,
+    )?;
+    info!("Watching {} for config changes", path);
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    std::thread::spawn(move || loop {
+        match inotify.read_events() {
+            Ok(events) => {
+                if events.iter().any(|e| e.name.as_ref() == Some(&name)) && tx.send(()).is_err() {
+                    return;
+                }
+            }
+            Err(e) => {
+                error!("inotify: error reading events: {}", e);
+                return;
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        while rx.recv().await.is_some() {
+            // Collapse bursts of events (e.g. several writes in a row) into a single reload.
+            while rx.try_recv().is_ok() {}
+            info!("{} changed, reloading", path);
+            let res = match RunConfig::load(&path) {
+                Ok(run_config) => reload(&state, run_config).await,
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(reapplied) => info!("reload: reapplied {:?}", reapplied),
+                Err(e) => error!("reload from {} failed: {:#}", path, e),
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,715 @@
+use super::audit::ExecAudit;
+use super::config::{ApiGroup, ServerConfig, Transport};
+use super::state::{with_state, SharedState};
+use super::tls;
+use super::{
+    config, disks, dmesg, edit, events, exec, health, loglevel, logs, mounts, net, reload, sidecar,
+    snapshot, status, sys, tail, wait, ApiError, ErrorMessage,
+};
+use anyhow::{anyhow, Error};
+use futures::{Stream, StreamExt};
+use log::{info, warn};
+use std::collections::BTreeSet;
+use std::convert::Infallible;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+use tokio_vsock::{VsockAddr, VsockListener};
+use warp::filters::path::FullPath;
+use warp::http::{Method, StatusCode};
+use warp::Filter;
+
+const VSOCK_CID: u32 = 3;
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// How long to stop accepting after an error like EMFILE, which would otherwise leave the
+// listener readable and the accept loop spinning.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The API groups a listener serves. Looked up on every request, so a reload can move groups
+/// between listeners that stay open.
+#[derive(Debug, Clone, PartialEq)]
+struct Routing {
+    control: bool,
+    groups: BTreeSet<ApiGroup>,
+}
+
+impl fmt::Display for Routing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names: Vec<&str> = vec![];
+        if self.control {
+            names.push("control");
+        }
+        for group in &self.groups {
+            names.push(match group {
+                ApiGroup::Logs => "logs",
+                ApiGroup::Metrics => "metrics",
+            });
+        }
+        write!(f, "{}", names.join(", "))
+    }
+}
+
+/// Rejects (as not found) requests for a group this listener doesn't serve; `None` is the
+/// control group.
+fn serving(
+    routing: Arc<RwLock<Routing>>,
+    group: Option<ApiGroup>,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::any()
+        .and_then(move || {
+            let routing = routing.read().unwrap();
+            let served = match group {
+                None => routing.control,
+                Some(group) => routing.groups.contains(&group),
+            };
+            async move {
+                if served {
+                    Ok(())
+                } else {
+                    Err(warp::reject::not_found())
+                }
+            }
+        })
+        .untuple_one()
+}
+
+fn routes(
+    state: SharedState,
+    routing: Arc<RwLock<Routing>>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = Infallible> + Clone {
+    let v1 = warp::path("v1");
+
+    let status_show = v1.and(warp::path("status"));
+    let get_status = warp::get().and(status_show).map(status);
+
+    let sysinfo_index = v1.and(warp::path("sysinfo"));
+
+    let get_sysinfo = warp::get()
+        .and(sysinfo_index)
+        .and(needs_proc())
+        .map(sys::list_sysinfo);
+    let post_exec = v1
+        .and(warp::path("exec"))
+        .and(warp::post())
+        .and(
+            rate_limited(state.clone())
+                .and(with_state(state.clone()))
+                .and(warp::header::optional::<String>("accept"))
+                .and(warp::body::json())
+                .or_else(audit_exec_rejection(state.clone())),
+        )
+        .and_then(exec::handle_exec);
+    let post_reload = v1
+        .and(warp::path("reload"))
+        .and(warp::post())
+        .and(with_state(state.clone()))
+        .and(warp::body::bytes())
+        .and_then(reload::handle_reload);
+    let post_sync = v1
+        .and(warp::path("sync"))
+        .and(warp::post())
+        .and(warp::body::bytes())
+        .and_then(snapshot::handle_sync);
+    let post_thaw = v1
+        .and(warp::path("thaw"))
+        .and(warp::post())
+        .map(snapshot::handle_thaw);
+    let get_config = v1
+        .and(warp::path("config"))
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(config::show_config);
+
+    let get_health = v1
+        .and(warp::path("health"))
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(health::show_health);
+    let get_tail = v1
+        .and(warp::path("tail"))
+        .and(warp::get())
+        .and(warp::query())
+        .and_then(tail::handle_tail);
+    let get_logs = v1
+        .and(warp::path("logs"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query())
+        .and_then(logs::handle_logs);
+    let get_network = v1
+        .and(warp::path("network"))
+        .and(warp::get())
+        .and_then(net::show_network);
+    let loglevel_show = v1.and(warp::path("loglevel"));
+    let get_loglevel = loglevel_show.and(warp::get()).map(loglevel::show_log_level);
+    let post_loglevel = loglevel_show
+        .and(warp::post())
+        .and(warp::body::json())
+        .map(loglevel::handle_log_level);
+    let get_disks = v1
+        .and(warp::path("disks"))
+        .and(warp::get())
+        .and(needs_proc())
+        .map(disks::list_disks);
+    let post_process = v1
+        .and(warp::path("process"))
+        .and(warp::path::param::<String>())
+        .and(warp::post());
+    let post_process_stop = post_process
+        .and(warp::path("stop"))
+        .and(warp::path::end())
+        .and(with_state(state.clone()))
+        .and_then(sidecar::handle_stop);
+    let post_process_start = post_process
+        .and(warp::path("start"))
+        .and(warp::path::end())
+        .and(with_state(state.clone()))
+        .and_then(sidecar::handle_start);
+    let post_file_edit = v1
+        .and(warp::path("file"))
+        .and(warp::path("edit"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::json())
+        .and_then(edit::handle_edit);
+    let get_wait = v1
+        .and(warp::path("wait"))
+        .and(warp::get())
+        .and(warp::query())
+        .and(with_state(state.clone()))
+        .and_then(wait::handle_wait);
+    let get_events = v1
+        .and(warp::path("events"))
+        .and(warp::get())
+        .map(events::handle_events);
+    let get_dmesg = v1
+        .and(warp::path("dmesg"))
+        .and(warp::get())
+        .and(warp::query())
+        .map(dmesg::show_dmesg);
+
+    let control = serving(routing.clone(), None).and(combine!(
+        get_status,
+        post_exec,
+        post_reload,
+        post_sync,
+        post_thaw,
+        get_config,
+        get_loglevel,
+        post_loglevel,
+        get_network,
+        get_health,
+        get_wait,
+        post_process_stop,
+        post_process_start,
+        post_file_edit
+    ));
+    let logs = serving(routing.clone(), Some(ApiGroup::Logs))
+        .and(combine!(get_logs, get_tail, get_dmesg, get_events));
+    let metrics = serving(routing, Some(ApiGroup::Metrics)).and(combine!(get_sysinfo, get_disks));
+
+    authorized(state)
+        .and(combine!(control, logs, metrics))
+        .recover(handle_rejection)
+}
+
+/// For endpoints that read `/proc`: a 503 if it couldn't be mounted.
+fn needs_proc() -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::any()
+        .and_then(|| async {
+            if mounts::proc_mounted() {
+                Ok(())
+            } else {
+                Err(warp::reject::custom(ApiError::Unavailable(
+                    "/proc isn't mounted".to_string(),
+                )))
+            }
+        })
+        .untuple_one()
+}
+
+/// Checks the bearer token, if one is configured. The token is read from the current config
+/// on every request so that a reload can rotate it. Exec requests turned away are written to
+/// the audit log.
+fn authorized(state: SharedState) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(with_state(state))
+        .and_then(
+            |header: Option<String>, method: Method, path: FullPath, state: SharedState| async move {
+                let server = state.config().server;
+                let token = match server.auth_token {
+                    Some(token) => token,
+                    None => return Ok(()),
+                };
+                let presented = header.as_deref().and_then(|h| h.strip_prefix("Bearer "));
+                let message = match presented {
+                    Some(presented)
+                        if constant_time_eq(presented.as_bytes(), token.as_bytes()) =>
+                    {
+                        return Ok(());
+                    }
+                    Some(_) => "invalid bearer token",
+                    None => "missing bearer token",
+                };
+                if method == Method::POST && is_exec_path(path.as_str()) {
+                    ExecAudit::rejected(server.audit_log, true, message);
+                }
+                Err(warp::reject::custom(ApiError::Unauthorized(
+                    message.to_string(),
+                )))
+            },
+        )
+        .untuple_one()
+}
+
+// The path `post_exec` matches: /v1/exec, and anything below it.
+fn is_exec_path(path: &str) -> bool {
+    let mut segments = path.split('/').filter(|s| !s.is_empty());
+    segments.next() == Some("v1") && segments.next() == Some("exec")
+}
+
+/// For the filters ahead of the exec handler: writes an audit entry for a request they turn
+/// away (the rate limit, a body that doesn't parse), then passes the rejection on.
+fn audit_exec_rejection<T>(
+    state: SharedState,
+) -> impl Fn(warp::Rejection) -> futures::future::Ready<Result<T, warp::Rejection>> + Clone {
+    move |err| {
+        let server = state.config().server;
+        let (_, message) = describe_rejection(&err);
+        ExecAudit::rejected(server.audit_log, server.auth_token.is_some(), &message);
+        futures::future::ready(Err(err))
+    }
+}
+
+fn rate_limited(state: SharedState) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    with_state(state)
+        .and_then(|state: SharedState| async move {
+            if state.take_exec_token() {
+                Ok(())
+            } else {
+                Err(warp::reject::custom(ApiError::TooManyRequests(
+                    "exec rate limit exceeded".to_string(),
+                )))
+            }
+        })
+        .untuple_one()
+}
+
+// Compares without returning early, so response timing doesn't reveal how much of a guessed
+// token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn handle_rejection(err: warp::Rejection) -> Result<impl warp::Reply, Infallible> {
+    let (status, message) = describe_rejection(&err);
+    Ok(warp::reply::with_status(
+        warp::reply::json(&ErrorMessage { message }),
+        status,
+    ))
+}
+
+fn describe_rejection(err: &warp::Rejection) -> (StatusCode, String) {
+    if let Some(e) = err.find::<ApiError>() {
+        match e {
+            ApiError::BadRequest(m) => (StatusCode::BAD_REQUEST, m.clone()),
+            ApiError::Unauthorized(m) => (StatusCode::UNAUTHORIZED, m.clone()),
+            ApiError::NotFound(m) => (StatusCode::NOT_FOUND, m.clone()),
+            ApiError::Internal(m) => (StatusCode::INTERNAL_SERVER_ERROR, m.clone()),
+            ApiError::Unavailable(m) => (StatusCode::SERVICE_UNAVAILABLE, m.clone()),
+            ApiError::TooManyRequests(m) => (StatusCode::TOO_MANY_REQUESTS, m.clone()),
+        }
+    } else if err.is_not_found() {
+        (StatusCode::NOT_FOUND, "not found".to_string())
+    } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::UnsupportedMediaType>() {
+        (StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string())
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method not allowed".to_string(),
+        )
+    } else {
+        (StatusCode::INTERNAL_SERVER_ERROR, format!("{:?}", err))
+    }
+}
+
+// The running servers, so that a reload can replace them.
+static SERVERS: Mutex<Option<Servers>> = Mutex::new(None);
+
+// Where the tasks of newly bound listeners go, for `shutdown::wait` to watch.
+static TASKS: OnceLock<mpsc::UnboundedSender<JoinHandle<bool>>> = OnceLock::new();
+
+struct Servers {
+    config: ServerConfig,
+    listeners: Vec<Server>,
+}
+
+/// Where a listener is bound.
+#[derive(Debug, Clone, PartialEq)]
+enum Address {
+    Vsock(u32),
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Address::Vsock(port) => write!(f, "vsock CID {}, port {}", VSOCK_CID, port),
+            Address::Tcp(addr) => write!(f, "tcp {}", addr),
+            Address::Unix(path) => write!(f, "unix {}", path.display()),
+        }
+    }
+}
+
+struct Server {
+    address: Address,
+    routing: Arc<RwLock<Routing>>,
+    // Read for every new connection, so TLS settings can change without rebinding.
+    tls: Arc<RwLock<Option<TlsAcceptor>>>,
+    // The listening socket, kept open by the accept loop for as long as this server runs.
+    listener: RawFd,
+    shutdown: oneshot::Sender<()>,
+}
+
+/// A client connection, plain or TLS.
+trait Connection: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Connection for T {}
+
+/// Binds the control API on the configured transport and serves it in the background.
+/// The routes are the same regardless of transport. Returns a receiver for the tasks of
+/// these listeners and every one a later restart binds; see `restart`.
+pub fn spawn(
+    config: &ServerConfig,
+    state: SharedState,
+) -> Result<mpsc::UnboundedReceiver<JoinHandle<bool>>, Error> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    TASKS.set(tx).ok();
+    restart(config, state)?;
+    Ok(rx)
+}
+
+/// The listeners `config` asks for: the main one, which serves the control group and any
+/// group without a port of its own, and one per other port.
+fn listeners(config: &ServerConfig) -> Result<Vec<(Address, Routing)>, Error> {
+    let main = match config.transport {
+        Transport::Vsock => Address::Vsock(config.vsock_port),
+        Transport::Tcp => Address::Tcp(config.tcp_address.parse()?),
+        Transport::Unix => {
+            if !config.ports.is_empty() {
+                return Err(anyhow!("ports can't be used with the unix transport"));
+            }
+            Address::Unix(PathBuf::from(&config.unix_path))
+        }
+    };
+    let routing = Routing {
+        control: true,
+        groups: ApiGroup::ALL
+            .into_iter()
+            .filter(|g| !config.ports.contains_key(g))
+            .collect(),
+    };
+    let mut listeners = vec![(main.clone(), routing)];
+    for (&group, &port) in &config.ports {
+        let address = match &main {
+            Address::Vsock(_) => Address::Vsock(port),
+            Address::Unix(_) => unreachable!("checked above"),
+            &Address::Tcp(mut addr) => {
+                addr.set_port(
+                    port.try_into()
+                        .map_err(|_| anyhow!("port {} is out of range for TCP", port))?,
+                );
+                Address::Tcp(addr)
+            }
+        };
+        match listeners.iter_mut().find(|(a, _)| *a == address) {
+            Some((_, routing)) => {
+                routing.groups.insert(group);
+            }
+            None => listeners.push((
+                address,
+                Routing {
+                    control: false,
+                    groups: [group].into(),
+                },
+            )),
+        }
+    }
+    Ok(listeners)
+}
+
+/// Moves the control API to the listeners described by `config`. New listeners are bound
+/// before any old one is shut down, so a bad config leaves the old servers running. An old
+/// server stops accepting connections straight away but finishes the requests it is already
+/// handling (including, typically, the reload request that triggered this) before its socket
+/// is closed. Listeners that stay put keep their socket: changes to the TLS settings, the
+/// backlog, or which groups they serve are applied to them in place.
+///
+/// The tasks of the listeners it binds are sent to the receiver `spawn` returned. Each
+/// finishes when its listener stops, with whether it was asked to (by a later restart)
+/// rather than failing.
+pub fn restart(config: &ServerConfig, state: SharedState) -> Result<(), Error> {
+    let mut current = SERVERS.lock().unwrap();
+    let wanted = listeners(config)?;
+    let old_config = current.as_ref().map(|servers| &servers.config);
+    let tls_changed = old_config.is_some_and(|c| c.tls != config.tls);
+    let backlog_changed = old_config.is_some_and(|c| c.listen_backlog != config.listen_backlog);
+    let acceptor = if tls_changed {
+        config.tls.as_ref().map(tls::acceptor).transpose()?
+    } else {
+        None
+    };
+    let (old_config, old) = match current.take() {
+        Some(servers) => (Some(servers.config), servers.listeners),
+        None => (None, vec![]),
+    };
+
+    let mut bound = vec![];
+    let mut tasks = vec![];
+    for (address, routing) in &wanted {
+        if old.iter().any(|s| s.address == *address) {
+            continue;
+        }
+        match bind(config, state.clone(), address.clone(), routing.clone()) {
+            Ok((server, task)) => {
+                bound.push(server);
+                tasks.push(task);
+            }
+            Err(e) => {
+                // Dropping the new servers' shutdown senders stops them again.
+                if let Some(config) = old_config {
+                    *current = Some(Servers {
+                        config,
+                        listeners: old,
+                    });
+                }
+                return Err(e.context(address.to_string()));
+            }
+        }
+    }
+
+    let mut listeners = vec![];
+    for server in old {
+        let Some((_, routing)) = wanted.iter().find(|(a, _)| *a == server.address) else {
+            server.shutdown.send(()).ok();
+            continue;
+        };
+        if *server.routing.read().unwrap() != *routing {
+            info!("Serving {} on {}", routing, server.address);
+            *server.routing.write().unwrap() = routing.clone();
+        }
+        if tls_changed {
+            *server.tls.write().unwrap() = acceptor.clone();
+        }
+        if backlog_changed {
+            if let Err(e) = listen(server.listener, config.listen_backlog) {
+                warn!("Failed to set the backlog on {}: {}", server.address, e);
+            }
+        }
+        listeners.push(server);
+    }
+    if tls_changed {
+        info!("Updated control API TLS settings");
+    }
+    if backlog_changed {
+        info!("Set control API backlog to {}", config.listen_backlog);
+    }
+    listeners.extend(bound);
+    *current = Some(Servers {
+        config: config.clone(),
+        listeners,
+    });
+    if let Some(tx) = TASKS.get() {
+        for task in tasks {
+            tx.send(task).ok();
+        }
+    }
+    Ok(())
+}
+
+fn bind(
+    config: &ServerConfig,
+    state: SharedState,
+    address: Address,
+    routing: Routing,
+) -> Result<(Server, JoinHandle<bool>), Error> {
+    let tls = Arc::new(RwLock::new(
+        config.tls.as_ref().map(tls::acceptor).transpose()?,
+    ));
+    let (conn_tx, mut conn_rx) = mpsc::unbounded_channel();
+    // What was asked for is kept to compare against later configs; what was bound (the
+    // actual port, when the config asks for any) is what gets logged.
+    let (bound, listener_fd) = match &address {
+        &Address::Vsock(port) => {
+            let listener = VsockListener::bind(VsockAddr::new(VSOCK_CID, port))?;
+            let fd = listener.as_raw_fd();
+            listen(fd, config.listen_backlog)?;
+            tokio::spawn(accept(listener.incoming(), tls.clone(), conn_tx));
+            (address.clone(), fd)
+        }
+        &Address::Tcp(addr) => {
+            let listener = std::net::TcpListener::bind(addr)?;
+            listener.set_nonblocking(true)?;
+            let listener = TcpListener::from_std(listener)?;
+            let fd = listener.as_raw_fd();
+            listen(fd, config.listen_backlog)?;
+            let bound = Address::Tcp(listener.local_addr()?);
+            let incoming = futures::stream::poll_fn(move |cx| {
+                listener
+                    .poll_accept(cx)
+                    .map(|res| Some(res.map(|(s, _)| s)))
+            });
+            tokio::spawn(accept(incoming, tls.clone(), conn_tx));
+            (bound, fd)
+        }
+        Address::Unix(path) => {
+            remove_stale_socket(path)?;
+            let listener = UnixListener::bind(path)?;
+            let fd = listener.as_raw_fd();
+            listen(fd, config.listen_backlog)?;
+            let incoming = futures::stream::poll_fn(move |cx| {
+                listener
+                    .poll_accept(cx)
+                    .map(|res| Some(res.map(|(s, _)| s)))
+            });
+            tokio::spawn(accept(incoming, tls.clone(), conn_tx));
+            (address.clone(), fd)
+        }
+    };
+    let mode = if config.tls.is_some() { ", mTLS" } else { "" };
+    info!("Listening on {} ({}{})", bound, routing, mode);
+
+    // Sending on (or dropping) `shutdown` is the only way a listener is meant to stop.
+    let (shutdown, rx) = oneshot::channel::<()>();
+    let requested = Arc::new(AtomicBool::new(false));
+    let signal = {
+        let requested = requested.clone();
+        async move {
+            rx.await.ok();
+            requested.store(true, Ordering::SeqCst);
+        }
+    };
+    let routing = Arc::new(RwLock::new(routing));
+    let incoming = futures::stream::poll_fn(move |cx| conn_rx.poll_recv(cx));
+    let server = warp::serve(routes(state, routing.clone()))
+        .serve_incoming_with_graceful_shutdown(incoming, signal);
+    let task = tokio::spawn(async move {
+        log_stopped(server, bound.to_string()).await;
+        requested.load(Ordering::SeqCst)
+    });
+
+    Ok((
+        Server {
+            address,
+            routing,
+            tls,
+            listener: listener_fd,
+            shutdown,
+        },
+        task,
+    ))
+}
+
+// A socket file outlives its listener, so one left by an earlier server (or an earlier boot
+// on a persistent /run) would make the bind fail. Anything that isn't a socket is left alone.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+// The socket libraries listen with a fixed backlog; calling listen(2) again on a listening
+// socket just changes it.
+fn listen(fd: RawFd, backlog: u32) -> io::Result<()> {
+    let backlog = backlog.min(libc::c_int::MAX as u32) as libc::c_int;
+    if unsafe { libc::listen(fd, backlog) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Accepts connections and hands them to the server, doing the TLS handshake first when TLS
+/// is enabled. Handshakes run in their own tasks so a slow client can't hold up the others.
+/// Accept errors never stop the loop: errors about a single connection are skipped, and
+/// anything else (like running out of file descriptors) pauses accepting briefly. Returns
+/// (closing the listener) once the server stops taking connections.
+async fn accept<S, C>(
+    mut incoming: S,
+    tls: Arc<RwLock<Option<TlsAcceptor>>>,
+    conns: mpsc::UnboundedSender<io::Result<Box<dyn Connection>>>,
+) where
+    S: Stream<Item = io::Result<C>> + Unpin,
+    C: Connection + 'static,
+{
+    loop {
+        let conn = tokio::select! {
+            _ = conns.closed() => return,
+            conn = incoming.next() => conn,
+        };
+        let conn = match conn {
+            Some(Ok(conn)) => conn,
+            Some(Err(e)) => {
+                warn!("Failed to accept control API connection: {}", e);
+                if !is_connection_error(&e) {
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                }
+                continue;
+            }
+            None => return,
+        };
+        let acceptor = tls.read().unwrap().clone();
+        match acceptor {
+            None => {
+                conns.send(Ok(Box::new(conn))).ok();
+            }
+            Some(acceptor) => {
+                let conns = conns.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(conn)).await {
+                        Ok(Ok(conn)) => {
+                            conns.send(Ok(Box::new(conn))).ok();
+                        }
+                        Ok(Err(e)) => warn!("Rejected control API connection: {}", e),
+                        Err(_) => warn!("Rejected control API connection: TLS handshake timed out"),
+                    }
+                });
+            }
+        }
+    }
+}
+
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::Interrupted
+    )
+}
+
+async fn log_stopped(server: impl Future<Output = ()>, description: String) {
+    server.await;
+    info!("Stopped listening on {}", description);
+}
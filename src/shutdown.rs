@@ -0,0 +1,261 @@
+use super::config::{HookConfig, RunConfig};
+use super::entrypoint;
+use super::events::{self, Event};
+use super::ops;
+use super::process::{self, ChildOptions};
+use super::reaper;
+use anyhow::{anyhow, Error};
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::{error, info, warn};
+use nix::errno::Errno;
+use nix::sys::signal::{kill, killpg, Signal};
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::future::pending;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Instant};
+
+const GRACE_PERIOD: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A process init started and stops in order at shutdown, together with its process group.
+#[derive(Debug, Clone)]
+struct Supervised {
+    name: String,
+    pgid: Pid,
+    priority: i32,
+    grace_period: Duration,
+    stop_signal: Signal,
+}
+
+static SUPERVISED: Mutex<Vec<Supervised>> = Mutex::new(Vec::new());
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// Registers a process group leader to be stopped by `stop_supervised`, replacing an earlier
+/// process of the same name (one that was restarted). It's asked to stop with `stop_signal`.
+pub fn supervise(
+    name: &str,
+    pid: u32,
+    priority: i32,
+    grace_period: Option<Duration>,
+    stop_signal: Signal,
+) {
+    let mut supervised = SUPERVISED.lock().unwrap();
+    supervised.retain(|s| s.name != name);
+    supervised.push(Supervised {
+        name: name.to_string(),
+        pgid: Pid::from_raw(pid as i32),
+        priority,
+        grace_period: grace_period.unwrap_or(GRACE_PERIOD),
+        stop_signal,
+    });
+}
+
+/// Whether shutdown has started stopping supervised processes, which mustn't be restarted
+/// from then on.
+pub fn stopping() -> bool {
+    STOPPING.load(Ordering::SeqCst)
+}
+
+/// Waits until init should shut down: a SIGTERM or SIGINT (the kernel's ctrl-alt-del), the
+/// entrypoint exiting, or a control API listener (one of the `servers` tasks) stopping
+/// without being asked to, which would leave the guest unreachable. Returns the reason, for
+/// logging.
+pub async fn wait(
+    entrypoint: Option<JoinHandle<()>>,
+    servers: mpsc::UnboundedReceiver<JoinHandle<bool>>,
+) -> &'static str {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to create signal handler");
+    let mut sigint = signal(SignalKind::interrupt()).expect("Failed to create signal handler");
+    let entrypoint_exit = async {
+        match entrypoint {
+            Some(handle) => {
+                handle.await.ok();
+            }
+            None => pending().await,
+        }
+    };
+    tokio::select! {
+        _ = sigterm.recv() => {
+            events::emit(Event::Signal { signal: "SIGTERM" });
+            "received SIGTERM"
+        }
+        _ = sigint.recv() => {
+            events::emit(Event::Signal { signal: "SIGINT" });
+            "received SIGINT"
+        }
+        _ = entrypoint_exit => "the entrypoint exited",
+        _ = server_failure(servers) => "the control API stopped",
+    }
+}
+
+/// Finishes when one of the listener tasks fails. Listeners a reload moves stop on request,
+/// and the ones it binds in their place arrive on `servers` and are watched from then on.
+async fn server_failure(mut servers: mpsc::UnboundedReceiver<JoinHandle<bool>>) {
+    let mut running = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            Some(task) = servers.recv() => running.push(task),
+            Some(stopped) = running.next(), if !running.is_empty() => match stopped {
+                Ok(true) => {}
+                Ok(false) => {
+                    error!("A control API listener stopped unexpectedly");
+                    return;
+                }
+                Err(e) => {
+                    error!("A control API listener failed: {}", e);
+                    return;
+                }
+            },
+            else => pending().await,
+        }
+    }
+}
+
+/// Runs the `pre_stop` hooks one after another. A hook that fails or times out is logged and
+/// the rest still run.
+pub async fn run_pre_stop(run_config: &RunConfig) {
+    if run_config.pre_stop.is_empty() {
+        return;
+    }
+    let env = match entrypoint::environment(run_config) {
+        Ok(env) => env,
+        Err(e) => {
+            error!("Not running pre_stop hooks: {:#}", e);
+            return;
+        }
+    };
+    for hook in &run_config.pre_stop {
+        match run_hook(hook, &env).await {
+            Ok(()) => info!("pre_stop hook {:?} finished", hook.cmd),
+            Err(e) => error!("pre_stop hook {:?}: {:#}", hook.cmd, e),
+        }
+    }
+}
+
+async fn run_hook(hook: &HookConfig, env: &HashMap<String, String>) -> Result<(), Error> {
+    let name = hook.cmd.first().ok_or_else(|| anyhow!("cmd is empty"))?;
+    let program =
+        process::resolve_program(name, env.get("PATH").map(String::as_str)).map_err(Error::msg)?;
+    info!("Running pre_stop hook {:?}", hook.cmd);
+    let mut cmd = Command::new(program);
+    cmd.arg0(name).args(&hook.cmd[1..]).envs(env);
+    // A group of its own, so a timeout kills whatever the hook started as well.
+    process::apply(
+        &mut cmd,
+        &ChildOptions {
+            new_process_group: true,
+            ..Default::default()
+        },
+    );
+    let (child, exited) = reaper::spawn(&mut cmd)?;
+    let pgid = Pid::from_raw(child.id().unwrap_or_default() as i32);
+
+    let timeout = Duration::from_millis(hook.timeout_ms);
+    let result = tokio::time::timeout(timeout, exited).await;
+    // Nothing the hook left behind should outlive it; this also ends a hook that timed out.
+    killpg(pgid, Signal::SIGKILL).ok();
+    drop(child);
+    match result {
+        Ok(Ok(exit)) if exit.code == Some(0) => Ok(()),
+        Ok(Ok(exit)) => Err(anyhow!(
+            "exited with code {:?}, signal {:?}",
+            exit.code,
+            exit.signal
+        )),
+        Ok(Err(_)) => Err(anyhow!("lost track of its exit status")),
+        Err(_) => Err(anyhow!("timed out after {:?}, killed it", timeout)),
+    }
+}
+
+/// Stops the supervised processes by ascending priority, each with its own grace period
+/// before it is killed. Processes with the same priority are stopped together, and the next
+/// priority waits until they are all gone.
+pub async fn stop_supervised() {
+    STOPPING.store(true, Ordering::SeqCst);
+    let mut supervised = SUPERVISED.lock().unwrap().clone();
+    supervised.sort_by_key(|s| s.priority);
+    for batch in supervised.chunk_by(|a, b| a.priority == b.priority) {
+        futures::future::join_all(batch.iter().map(stop_group)).await;
+    }
+}
+
+/// Stops one supervised process group the way shutdown does. Returns whether it had to be
+/// killed, or None if nothing by that name is supervised.
+pub async fn stop(name: &str) -> Option<bool> {
+    let process = SUPERVISED
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|s| s.name == name)
+        .cloned()?;
+    Some(stop_group(&process).await)
+}
+
+// Returns whether the group had to be killed.
+async fn stop_group(process: &Supervised) -> bool {
+    if killpg(process.pgid, process.stop_signal) == Err(Errno::ESRCH) {
+        return false;
+    }
+    info!(
+        "Stopping {} with {} (priority {})",
+        process.name, process.stop_signal, process.priority
+    );
+    let deadline = Instant::now() + process.grace_period;
+    while Instant::now() < deadline {
+        if killpg(process.pgid, None) == Err(Errno::ESRCH) {
+            info!("{} exited", process.name);
+            return false;
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+    warn!(
+        "{} still running after {:?}, sending SIGKILL",
+        process.name, process.grace_period
+    );
+    killpg(process.pgid, Signal::SIGKILL).ok();
+    true
+}
+
+/// Stops every other process in the guest: SIGTERM first, then SIGKILL for anything still
+/// around after the grace period. The reaper collects them as they exit.
+pub async fn terminate_all() {
+    info!("Sending SIGTERM to all processes");
+    if let Err(e) = kill(Pid::from_raw(-1), Signal::SIGTERM) {
+        if e == Errno::ESRCH {
+            return;
+        }
+        warn!("Failed to signal processes: {}", e);
+    }
+
+    let deadline = Instant::now() + GRACE_PERIOD;
+    while Instant::now() < deadline {
+        // Signal 0 only checks whether there is anything left to signal.
+        if kill(Pid::from_raw(-1), None) == Err(Errno::ESRCH) {
+            info!("All processes exited");
+            return;
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+
+    warn!(
+        "Processes still running after {:?}, sending SIGKILL",
+        GRACE_PERIOD
+    );
+    kill(Pid::from_raw(-1), Signal::SIGKILL).ok();
+}
+
+/// Flushes filesystems and stops the VM. This doesn't return.
+pub fn power_off() -> ! {
+    info!("Syncing filesystems and stopping the VM");
+    nix::unistd::sync();
+    // Firecracker has no power-off device; a reboot request is what makes the VMM exit.
+    let res = ops::reboot();
+    panic!("reboot failed: {:?}", res);
+}
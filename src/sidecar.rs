@@ -0,0 +1,332 @@
+//! Supervising the configured sidecars: starting them, restarting them according to their
+//! policy, and keeping their status in the shared state for `/v1/health`. Each can also be
+//! stopped and started again through `/v1/process/:name`.
+
+use super::config::{RestartPolicy, RunConfig, SidecarConfig};
+use super::entrypoint;
+use super::events::{self, Event};
+use super::logs;
+use super::probe;
+use super::process::{self, ChildOptions};
+use super::reaper::{self, Exit};
+use super::shutdown;
+use super::state::{SharedState, SidecarState};
+use super::ApiError;
+use anyhow::{anyhow, Error};
+use log::{error, info, warn};
+use nix::unistd::User;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::pending;
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::watch;
+use tokio::time::{sleep, Instant};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// A sidecar that ran at least this long before exiting is restarted without delay.
+const STABLE_RUN: Duration = Duration::from_secs(10);
+/// How long a stop request waits for the exit status once the process group is gone.
+const EXIT_STATUS_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Whether each sidecar should be running, as set through `/v1/process/:name`. Every send
+// wakes a sidecar that is waiting to be started, even if the value stays the same.
+static WANTED: Mutex<BTreeMap<String, watch::Sender<bool>>> = Mutex::new(BTreeMap::new());
+
+/// Starts every configured sidecar in the background. A sidecar with a duplicate or reserved
+/// name is logged and skipped.
+pub fn start_all(run_config: &RunConfig, state: SharedState) {
+    let mut names = HashSet::new();
+    for sidecar in &run_config.sidecars {
+        if sidecar.name == "entrypoint" || !names.insert(sidecar.name.as_str()) {
+            error!(
+                "Not starting sidecar {:?}: name is already taken",
+                sidecar.name
+            );
+            continue;
+        }
+        state.update_sidecar(&sidecar.name, |_| {});
+        let env = match environment(run_config, sidecar) {
+            Ok(env) => env,
+            Err(e) => {
+                error!("Not starting sidecar {}: {:#}", sidecar.name, e);
+                state.update_sidecar(&sidecar.name, |s| s.state = SidecarState::Failed);
+                continue;
+            }
+        };
+        let (wanted, rx) = watch::channel(true);
+        WANTED.lock().unwrap().insert(sidecar.name.clone(), wanted);
+        tokio::spawn(supervise(sidecar.clone(), env, state.clone(), rx));
+    }
+}
+
+fn environment(
+    run_config: &RunConfig,
+    sidecar: &SidecarConfig,
+) -> Result<HashMap<String, String>, Error> {
+    let mut env = entrypoint::environment(run_config)?;
+    env.extend(sidecar.env.clone());
+    Ok(env)
+}
+
+/// Runs the sidecar until shutdown begins, restarting it according to its policy. Once it
+/// has exited for good or been stopped, it waits to be started again.
+async fn supervise(
+    sidecar: SidecarConfig,
+    env: HashMap<String, String>,
+    state: SharedState,
+    mut wanted: watch::Receiver<bool>,
+) {
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        wanted.borrow_and_update();
+        let started = Instant::now();
+        let result = run_once(&sidecar, &env, &state).await;
+        if shutdown::stopping() {
+            return;
+        }
+        let failed = match &result {
+            Ok(exit) => {
+                info!(
+                    "Sidecar {} exited (code {:?}, signal {:?})",
+                    sidecar.name, exit.code, exit.signal
+                );
+                events::emit(Event::Exited {
+                    name: sidecar.name.clone(),
+                    code: exit.code,
+                    signal: exit.signal,
+                });
+                exit.code != Some(0)
+            }
+            Err(e) => {
+                error!("Sidecar {}: {:#}", sidecar.name, e);
+                true
+            }
+        };
+        let stopped = !*wanted.borrow();
+        let restart = !stopped
+            && match sidecar.restart {
+                RestartPolicy::Never => false,
+                RestartPolicy::OnFailure => failed,
+                RestartPolicy::Always => true,
+            };
+        state.update_sidecar(&sidecar.name, |s| {
+            s.pid = None;
+            s.ready = None;
+            if let Ok(exit) = &result {
+                s.last_exit_code = exit.code;
+                s.last_exit_signal = exit.signal;
+            }
+            s.state = match (stopped, restart, failed) {
+                (true, _, _) => SidecarState::Stopped,
+                (false, true, _) => SidecarState::Restarting,
+                (false, false, false) => SidecarState::Exited,
+                (false, false, true) => SidecarState::Failed,
+            };
+        });
+        if !restart {
+            wait_for_start(&mut wanted).await;
+            backoff = INITIAL_BACKOFF;
+            continue;
+        }
+
+        if started.elapsed() >= STABLE_RUN {
+            backoff = INITIAL_BACKOFF;
+        }
+        warn!("Restarting sidecar {} in {:?}", sidecar.name, backoff);
+        events::emit(Event::Restarting {
+            name: sidecar.name.clone(),
+            delay_ms: backoff.as_millis(),
+        });
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+        if shutdown::stopping() {
+            return;
+        }
+        if !*wanted.borrow() {
+            state.update_sidecar(&sidecar.name, |s| s.state = SidecarState::Stopped);
+            wait_for_start(&mut wanted).await;
+            backoff = INITIAL_BACKOFF;
+        }
+        state.update_sidecar(&sidecar.name, |s| s.restarts += 1);
+    }
+}
+
+async fn wait_for_start(wanted: &mut watch::Receiver<bool>) {
+    loop {
+        // The senders live in WANTED for good, so this only fails if init is going away.
+        if wanted.changed().await.is_err() {
+            pending::<()>().await;
+        }
+        if *wanted.borrow_and_update() {
+            return;
+        }
+    }
+}
+
+/// Starts the sidecar (and its readiness probe) and waits for it to exit.
+async fn run_once(
+    sidecar: &SidecarConfig,
+    env: &HashMap<String, String>,
+    state: &SharedState,
+) -> Result<Exit, Error> {
+    let name = sidecar.cmd.first().ok_or_else(|| anyhow!("cmd is empty"))?;
+    let program =
+        process::resolve_program(name, env.get("PATH").map(String::as_str)).map_err(Error::msg)?;
+    let mut cmd = Command::new(program);
+    cmd.arg0(name)
+        .args(&sidecar.cmd[1..])
+        .envs(env)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if let Some(dir) = &sidecar.working_dir {
+        cmd.current_dir(dir);
+    }
+    let mut opts = ChildOptions {
+        new_session: true,
+        ..Default::default()
+    };
+    if let Some(user) = &sidecar.user {
+        let user = User::from_name(user)?.ok_or_else(|| anyhow!("unknown user {:?}", user))?;
+        opts.user = Some((user.uid, user.gid));
+    }
+    process::apply(&mut cmd, &opts);
+
+    info!("Starting sidecar {}: {:?}", sidecar.name, sidecar.cmd);
+    let (mut child, exited) = reaper::spawn(&mut cmd)
+        .map_err(|e| anyhow!("starting: {}", process::describe_spawn_error(&e, &opts)))?;
+    if let Some(stdout) = child.stdout.take() {
+        logs::capture(&sidecar.name, stdout);
+    }
+    if let Some(stderr) = child.stderr.take() {
+        logs::capture(&sidecar.name, stderr);
+    }
+    let pid = child.id();
+    state.process_started(&sidecar.name);
+    events::emit(Event::Started {
+        name: sidecar.name.clone(),
+        pid,
+    });
+    if let Some(pid) = pid {
+        let grace_period = sidecar.grace_period_ms.map(Duration::from_millis);
+        shutdown::supervise(
+            &sidecar.name,
+            pid,
+            sidecar.shutdown_priority,
+            grace_period,
+            sidecar.stop_signal,
+        );
+    }
+    state.update_sidecar(&sidecar.name, |s| {
+        s.state = SidecarState::Running;
+        s.pid = pid;
+    });
+
+    let probe = sidecar.readiness_probe.as_ref().and_then(|probe_config| {
+        let (state, sidecar_name) = (state.clone(), sidecar.name.clone());
+        let set_ready = move |ready| state.update_sidecar(&sidecar_name, |s| s.ready = Some(ready));
+        let name = format!("sidecar {}", sidecar.name);
+        probe::start(probe_config.clone(), env.clone(), name, set_ready)
+            .map_err(|e| error!("not running readiness probe: {:#}", e))
+            .ok()
+    });
+
+    let exit = exited.await;
+    if let Ok(exit) = &exit {
+        state.process_exited(&sidecar.name, exit);
+    }
+    // The probe mustn't mark a restarted sidecar ready from an earlier run.
+    if let Some(probe) = probe {
+        probe.abort();
+    }
+    drop(child);
+    exit.map_err(|_| anyhow!("lost track of its exit status"))
+}
+
+fn set_wanted(name: &str, running: bool) -> Result<(), ApiError> {
+    if name == "entrypoint" {
+        return Err(ApiError::BadRequest(
+            "the entrypoint can't be stopped or started on its own: the guest shuts down when \
+             it exits"
+                .to_string(),
+        ));
+    }
+    match WANTED.lock().unwrap().get(name) {
+        Some(wanted) => {
+            wanted.send_replace(running);
+            Ok(())
+        }
+        None => Err(ApiError::NotFound(format!("no sidecar named {}", name))),
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct StopResponse {
+    name: String,
+    /// Whether it was still running after its grace period and had to be killed.
+    killed: bool,
+    /// How it exited; null if it wasn't running.
+    exit_code: Option<i32>,
+    signal: Option<i32>,
+}
+
+/// Stops a sidecar (SIGTERM to its process group, then SIGKILL after its grace period) and
+/// keeps it from being restarted until it is started again.
+pub async fn handle_stop(
+    name: String,
+    state: SharedState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    info!("process stop: {}", name);
+    set_wanted(&name, false).map_err(warp::reject::custom)?;
+    let running = state.sidecars().get(&name).and_then(|s| s.pid).is_some();
+    let mut response = StopResponse {
+        name,
+        killed: false,
+        exit_code: None,
+        signal: None,
+    };
+    if running {
+        let exits = state.watch_exit(&response.name);
+        response.killed = shutdown::stop(&response.name).await.unwrap_or(false);
+        if let Some(mut exits) = exits {
+            let exited = exits.wait_for(Option::is_some);
+            if let Ok(Ok(exit)) = tokio::time::timeout(EXIT_STATUS_TIMEOUT, exited).await {
+                if let Some(exit) = exit.as_ref() {
+                    response.exit_code = exit.code;
+                    response.signal = exit.signal;
+                }
+            }
+        }
+    }
+    state.update_sidecar(&response.name, |s| {
+        if s.pid.is_none() {
+            s.state = SidecarState::Stopped;
+        }
+    });
+    Ok(warp::reply::json(&response))
+}
+
+/// Starts a sidecar that was stopped or has exited for good. Returns once it is due to start;
+/// `/v1/health` shows when it is running.
+pub async fn handle_start(
+    name: String,
+    state: SharedState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    info!("process start: {}", name);
+    if let Some(status) = state.sidecars().get(&name) {
+        if !matches!(
+            status.state,
+            SidecarState::Exited | SidecarState::Failed | SidecarState::Stopped
+        ) {
+            return Err(warp::reject::custom(ApiError::BadRequest(format!(
+                "{} is {:?}, not stopped",
+                name, status.state
+            ))));
+        }
+    }
+    set_wanted(&name, true).map_err(warp::reject::custom)?;
+    Ok(warp::reply::json(&serde_json::json!({ "name": name })))
+}
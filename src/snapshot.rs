@@ -0,0 +1,155 @@
+//! Flushing and freezing filesystems so the host can take a crash-consistent snapshot of the
+//! guest's disks.
+
+use super::{ApiError, ErrorMessage};
+use anyhow::{anyhow, Context, Error};
+use log::{info, warn};
+use nix::errno::Errno;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+
+// _IOWR('X', 119, int) and _IOWR('X', 120, int) from linux/fs.h.
+const FIFREEZE: libc::c_ulong = 0xc004_5877;
+const FITHAW: libc::c_ulong = 0xc004_5878;
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct SyncRequest {
+    /// Mountpoints to freeze after syncing. Writes to them block until `POST /v1/thaw`.
+    freeze: Vec<String>,
+    /// Thaw automatically after this long, so a host that never sends the thaw request
+    /// doesn't leave the guest hung.
+    thaw_after_ms: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+struct SyncResponse {
+    frozen: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct ThawResponse {
+    thawed: Vec<String>,
+}
+
+const DEFAULT_THAW_AFTER: Duration = Duration::from_secs(30);
+
+// Frozen mountpoints, and the timer that thaws them.
+static FROZEN: Mutex<Frozen> = Mutex::new(Frozen {
+    mountpoints: vec![],
+    auto_thaw: None,
+});
+
+struct Frozen {
+    mountpoints: Vec<String>,
+    auto_thaw: Option<JoinHandle<()>>,
+}
+
+fn ioctl(mountpoint: &str, request: libc::c_ulong) -> Result<(), Error> {
+    let dir = File::open(mountpoint).with_context(|| format!("opening {}", mountpoint))?;
+    Errno::result(unsafe { libc::ioctl(dir.as_raw_fd(), request, 0) })
+        .map(drop)
+        .with_context(|| mountpoint.to_string())
+}
+
+/// Thaws everything frozen, in the reverse of the order it was frozen in.
+fn thaw_all(frozen: &mut Frozen) -> Vec<String> {
+    if let Some(timer) = frozen.auto_thaw.take() {
+        timer.abort();
+    }
+    let mut thawed = vec![];
+    while let Some(mountpoint) = frozen.mountpoints.pop() {
+        match ioctl(&mountpoint, FITHAW) {
+            Ok(()) => info!("Thawed {}", mountpoint),
+            Err(e) => warn!("Failed to thaw {:#}", e),
+        }
+        thawed.push(mountpoint);
+    }
+    thawed
+}
+
+/// Flushes all filesystems, then freezes the requested ones. If any freeze fails, the ones
+/// already frozen are thawed again. Runs on a blocking thread: sync(2) can take a while with
+/// a lot of dirty data.
+fn sync_and_freeze(mountpoints: &[String]) -> Result<(), Error> {
+    let mut frozen = FROZEN.lock().unwrap();
+    // sync(2) would block until the frozen filesystems are thawed.
+    if !frozen.mountpoints.is_empty() {
+        return Err(anyhow!(
+            "filesystems are frozen: {}",
+            frozen.mountpoints.join(", ")
+        ));
+    }
+    nix::unistd::sync();
+    info!("Synced filesystems");
+
+    for mountpoint in mountpoints {
+        if let Err(e) = ioctl(mountpoint, FIFREEZE) {
+            thaw_all(&mut frozen);
+            return Err(e.context("freezing"));
+        }
+        info!("Froze {}", mountpoint);
+        frozen.mountpoints.push(mountpoint.clone());
+    }
+    Ok(())
+}
+
+async fn sync(req: SyncRequest) -> Result<Vec<String>, Error> {
+    let mountpoints = req.freeze.clone();
+    tokio::task::spawn_blocking(move || sync_and_freeze(&mountpoints)).await??;
+    if req.freeze.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let thaw_after = req
+        .thaw_after_ms
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_THAW_AFTER);
+    let timer = tokio::spawn(async move {
+        tokio::time::sleep(thaw_after).await;
+        let mut frozen = FROZEN.lock().unwrap();
+        // Thawing aborts the timer, but this task is the timer; take it out first.
+        frozen.auto_thaw.take();
+        warn!("No thaw request after {:?}, thawing", thaw_after);
+        thaw_all(&mut frozen);
+    });
+    FROZEN.lock().unwrap().auto_thaw = Some(timer);
+    Ok(req.freeze)
+}
+
+pub async fn handle_sync(body: Bytes) -> Result<impl warp::Reply, warp::Rejection> {
+    let req: SyncRequest = if body.is_empty() {
+        SyncRequest::default()
+    } else {
+        serde_json::from_slice(&body)
+            .map_err(|e| warp::reject::custom(ApiError::BadRequest(e.to_string())))?
+    };
+    let res = sync(req).await;
+
+    info!("sync: {:?}", res);
+
+    match res {
+        Ok(frozen) => Ok(warp::reply::with_status(
+            warp::reply::json(&SyncResponse { frozen }),
+            StatusCode::OK,
+        )),
+        Err(e) => Ok(warp::reply::with_status(
+            warp::reply::json(&ErrorMessage {
+                message: format!("{:#}", e),
+            }),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+pub fn handle_thaw() -> impl warp::Reply {
+    let thawed = thaw_all(&mut FROZEN.lock().unwrap());
+    info!("thaw: {:?}", thawed);
+    warp::reply::json(&ThawResponse { thawed })
+}
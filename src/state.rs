@@ -0,0 +1,182 @@
+use super::config::RunConfig;
+use super::files::WriteSummary;
+use super::ratelimit::TokenBucket;
+use super::reaper::Exit;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::watch;
+use warp::Filter;
+
+/// State shared between init's main task and the control API handlers.
+#[derive(Debug)]
+pub struct State {
+    ready: AtomicBool,
+    // None when there's no readiness probe.
+    entrypoint_ready: RwLock<Option<bool>>,
+    config: RwLock<RunConfig>,
+    exec_bucket: Mutex<TokenBucket>,
+    // From the last time files were written, at boot or on a reload.
+    files: RwLock<WriteSummary>,
+    sidecars: RwLock<BTreeMap<String, SidecarStatus>>,
+    // The last exit of each supervised process, or None while it runs, for `/v1/wait`.
+    exits: Mutex<HashMap<String, watch::Sender<Option<Exit>>>>,
+    // Why init is in recovery mode, if it is.
+    recovery: RwLock<Option<String>>,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct SidecarStatus {
+    pub state: SidecarState,
+    pub pid: Option<u32>,
+    pub restarts: u32,
+    /// Whether its readiness probe has passed since it last started; null without a probe.
+    pub ready: Option<bool>,
+    pub last_exit_code: Option<i32>,
+    pub last_exit_signal: Option<i32>,
+}
+
+impl SidecarStatus {
+    /// Running and ready, or finished successfully and not due to be restarted.
+    pub fn is_healthy(&self) -> bool {
+        match self.state {
+            SidecarState::Running => self.ready.unwrap_or(true),
+            SidecarState::Exited => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SidecarState {
+    #[default]
+    Starting,
+    Running,
+    /// Waiting to be started again after exiting.
+    Restarting,
+    /// Exited successfully and won't be restarted.
+    Exited,
+    /// Exited unsuccessfully or couldn't be started, and won't be restarted.
+    Failed,
+    /// Stopped through `/v1/process/:name/stop`, until it is started again.
+    Stopped,
+}
+
+impl State {
+    pub fn new(config: RunConfig) -> State {
+        State {
+            ready: AtomicBool::new(false),
+            entrypoint_ready: RwLock::new(None),
+            exec_bucket: Mutex::new(TokenBucket::new(&config.server.exec_rate_limit)),
+            config: RwLock::new(config),
+            files: RwLock::default(),
+            sidecars: RwLock::default(),
+            exits: Mutex::default(),
+            recovery: RwLock::default(),
+        }
+    }
+
+    /// The effective run config: what was loaded at boot, with any reloads applied.
+    pub fn config(&self) -> RunConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn update_config(&self, f: impl FnOnce(&mut RunConfig)) {
+        f(&mut self.config.write().unwrap());
+    }
+
+    /// Whether an exec request fits within the configured rate limit.
+    pub fn take_exec_token(&self) -> bool {
+        let limit = self.config.read().unwrap().server.exec_rate_limit.clone();
+        self.exec_bucket.lock().unwrap().try_take(&limit)
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+    }
+
+    /// Marks system setup as finished; exec requests are refused until then.
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::SeqCst);
+    }
+
+    pub fn recovery(&self) -> Option<String> {
+        self.recovery.read().unwrap().clone()
+    }
+
+    pub fn set_recovery(&self, problem: String) {
+        *self.recovery.write().unwrap() = Some(problem);
+    }
+
+    /// Whether the entrypoint's readiness probe has passed, if it has one.
+    pub fn entrypoint_ready(&self) -> Option<bool> {
+        *self.entrypoint_ready.read().unwrap()
+    }
+
+    pub fn set_entrypoint_ready(&self, ready: bool) {
+        *self.entrypoint_ready.write().unwrap() = Some(ready);
+    }
+
+    pub fn files_written(&self) -> WriteSummary {
+        *self.files.read().unwrap()
+    }
+
+    pub fn set_files_written(&self, summary: WriteSummary) {
+        *self.files.write().unwrap() = summary;
+    }
+
+    pub fn sidecars(&self) -> BTreeMap<String, SidecarStatus> {
+        self.sidecars.read().unwrap().clone()
+    }
+
+    pub fn update_sidecar(&self, name: &str, f: impl FnOnce(&mut SidecarStatus)) {
+        f(self
+            .sidecars
+            .write()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default());
+    }
+
+    pub fn process_started(&self, name: &str) {
+        self.exit_sender(name, |tx| {
+            tx.send_replace(None);
+        });
+    }
+
+    pub fn process_exited(&self, name: &str, exit: &Exit) {
+        self.exit_sender(name, |tx| {
+            tx.send_replace(Some(exit.clone()));
+        });
+    }
+
+    /// Follows the exits of a process init has started, or None if it never started one by
+    /// that name.
+    pub fn watch_exit(&self, name: &str) -> Option<watch::Receiver<Option<Exit>>> {
+        self.exits
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(|tx| tx.subscribe())
+    }
+
+    fn exit_sender(&self, name: &str, f: impl FnOnce(&watch::Sender<Option<Exit>>)) {
+        f(self
+            .exits
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_insert_with(|| watch::channel(None).0));
+    }
+}
+
+pub type SharedState = Arc<State>;
+
+pub fn with_state(
+    state: SharedState,
+) -> impl Filter<Extract = (SharedState,), Error = Infallible> + Clone {
+    warp::any().map(move || state.clone())
+}
@@ -1,10 +1,11 @@
+use super::reaper::{self, ReapCounts};
+use super::{ApiReply, ErrorMessage};
+use anyhow::Error;
+use log::info;
+use serde::Serialize;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
-use serde::Serialize;
-use log::info;
-use super::{ApiReply, ErrorMessage};
-use anyhow::Error;
 use warp::http::StatusCode;
 
 #[derive(Debug, Serialize, Clone)]
@@ -15,6 +16,8 @@ pub struct SysInfo {
     disks: Vec<DiskStat>,
     net: Vec<NetworkDevice>,
     filefd: FileFd,
+    /// Children init has reaped since it started.
+    reaped: ReapCounts,
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -92,11 +95,12 @@ fn collect_sysinfo() -> Result<SysInfo, Error> {
 
     let filefd = FileFd {
         allocated: splitted_fd.next().unwrap_or("0").trim().parse()?,
-        maximum: splitted_fd.skip(1).next().unwrap_or("0").trim().parse()?,
+        maximum: splitted_fd.nth(1).unwrap_or("0").trim().parse()?,
     };
 
     Ok(SysInfo {
         filefd,
+        reaped: reaper::counts(),
         memory: Memory {
             mem_total: meminfo.mem_total,
             mem_free: meminfo.mem_free,
@@ -218,7 +222,7 @@ impl DiskStat {
     }
 
     fn from_line(line: &str) -> Result<Option<DiskStat>, Error> {
-        let mut split = line.trim_start().split_whitespace();
+        let mut split = line.split_whitespace();
         let name = split
             .nth(2)
             .ok_or_else(|| StringError::from("name missing".to_owned()))?
@@ -286,7 +290,7 @@ impl From<String> for StringError {
     }
 }
 
-impl<'a> From<&'a str> for StringError {
+impl From<&str> for StringError {
     fn from(f: &str) -> StringError {
         StringError(f.into())
     }
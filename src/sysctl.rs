@@ -0,0 +1,42 @@
+use super::ops;
+use anyhow::{anyhow, Error};
+use log::{error, info};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Sets each kernel parameter, given in `sysctl` form (`net.ipv4.ip_forward`) or as a path
+/// under /proc/sys (`net/ipv4/ip_forward`). A parameter that can't be set is logged and
+/// skipped.
+pub fn apply(sysctls: &BTreeMap<String, String>) {
+    for (key, value) in sysctls {
+        match set(key, value) {
+            Ok(()) => info!("Set sysctl {} = {}", key, value),
+            Err(e) => error!("Failed to set sysctl {}: {:#}", key, e),
+        }
+    }
+}
+
+pub fn set(key: &str, value: &str) -> Result<(), Error> {
+    let path = proc_path(key)?;
+    let path = path.to_str().ok_or_else(|| anyhow!("invalid name"))?;
+    ops::write(path, value)?;
+    Ok(())
+}
+
+fn proc_path(key: &str) -> Result<PathBuf, Error> {
+    // Only a dotted name is translated; one with slashes already is a path (and may contain
+    // dots, as in net/ipv4/conf/eth0.100/forwarding).
+    let relative = if key.contains('/') {
+        key.to_string()
+    } else {
+        key.replace('.', "/")
+    };
+    if relative.is_empty()
+        || relative
+            .split('/')
+            .any(|c| c.is_empty() || c == "." || c == "..")
+    {
+        return Err(anyhow!("invalid name"));
+    }
+    Ok(PathBuf::from("/proc/sys").join(relative))
+}
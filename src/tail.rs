@@ -0,0 +1,157 @@
+use super::ApiError;
+use log::{debug, info};
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use warp::http::header::{HeaderValue, CONTENT_TYPE};
+use warp::hyper::body::Bytes;
+use warp::hyper::Body;
+use warp::reply::Response;
+
+const DEFAULT_LINES: usize = 10;
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Deserialize, Debug)]
+pub struct TailQuery {
+    path: String,
+    /// How many lines from the end of the file to start with.
+    lines: Option<usize>,
+    /// Keep the response open and stream lines as they're appended.
+    #[serde(default)]
+    follow: bool,
+}
+
+/// Reads backwards from the end of `file` until it has the last `n` lines. Returns them and
+/// the offset of the end of the file.
+fn last_lines(file: &mut File, n: usize) -> io::Result<(Vec<u8>, u64)> {
+    let end = file.seek(SeekFrom::End(0))?;
+    if n == 0 {
+        return Ok((vec![], end));
+    }
+    let mut start = end;
+    let mut tail = vec![];
+    // A trailing newline ends the last line rather than starting an empty one.
+    let mut newlines = 0;
+    let mut skip_final_newline = true;
+    while start > 0 {
+        let len = CHUNK_SIZE.min(start as usize);
+        start -= len as u64;
+        let mut chunk = vec![0; len];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut chunk)?;
+        for (i, &b) in chunk.iter().enumerate().rev() {
+            if b != b'\n' {
+                skip_final_newline = false;
+                continue;
+            }
+            if skip_final_newline {
+                skip_final_newline = false;
+                continue;
+            }
+            newlines += 1;
+            if newlines == n {
+                chunk.drain(..=i);
+                chunk.append(&mut tail);
+                return Ok((chunk, end));
+            }
+        }
+        chunk.append(&mut tail);
+        tail = chunk;
+    }
+    Ok((tail, end))
+}
+
+/// Polls `path` for appended data and sends it on, a complete line at a time. A file that
+/// shrinks (truncated) or is replaced (rotated) is read again from the start. Returns when
+/// the client goes away.
+async fn follow(path: String, mut file: File, mut offset: u64, tx: mpsc::Sender<Bytes>) {
+    let mut partial = vec![];
+    loop {
+        tokio::select! {
+            _ = tx.closed() => return,
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+        }
+        let rotated = match (std::fs::metadata(&path), file.metadata()) {
+            (Ok(current), Ok(open)) => current.ino() != open.ino() || current.dev() != open.dev(),
+            _ => false,
+        };
+        if rotated {
+            match File::open(&path) {
+                Ok(new) => {
+                    debug!("tail: {} was replaced, reopening", path);
+                    file = new;
+                    offset = 0;
+                    partial.clear();
+                }
+                Err(_) => continue,
+            }
+        }
+        let len = match file.metadata() {
+            Ok(m) => m.len(),
+            Err(_) => continue,
+        };
+        if len < offset {
+            debug!("tail: {} was truncated", path);
+            offset = 0;
+            partial.clear();
+        }
+        if len == offset {
+            continue;
+        }
+        let mut data = vec![];
+        let read = file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| (&mut file).take(len - offset).read_to_end(&mut data));
+        if read.is_err() {
+            continue;
+        }
+        offset += data.len() as u64;
+        partial.append(&mut data);
+        if let Some(last) = partial.iter().rposition(|&b| b == b'\n') {
+            let rest = partial.split_off(last + 1);
+            let lines = std::mem::replace(&mut partial, rest);
+            if tx.send(Bytes::from(lines)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+pub async fn handle_tail(query: TailQuery) -> Result<Response, warp::Rejection> {
+    info!("tail: {:?}", query);
+    let lines = query.lines.unwrap_or(DEFAULT_LINES);
+    let path = query.path.clone();
+    let (file, initial, offset) = tokio::task::spawn_blocking(move || {
+        let mut file = File::open(&path)?;
+        let (initial, offset) = last_lines(&mut file, lines)?;
+        Ok::<_, io::Error>((file, initial, offset))
+    })
+    .await
+    .map_err(|e| warp::reject::custom(ApiError::Internal(e.to_string())))?
+    .map_err(|e| {
+        warp::reject::custom(ApiError::BadRequest(format!(
+            "reading {}: {}",
+            query.path, e
+        )))
+    })?;
+
+    let body = if query.follow {
+        let (tx, mut rx) = mpsc::channel(16);
+        tx.send(Bytes::from(initial)).await.ok();
+        tokio::spawn(follow(query.path, file, offset, tx));
+        let stream = futures::stream::poll_fn(move |cx| rx.poll_recv(cx).map(|b| b.map(Ok)));
+        Body::wrap_stream::<_, _, io::Error>(stream)
+    } else {
+        Body::from(initial)
+    };
+    let mut response = Response::new(body);
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    Ok(response)
+}
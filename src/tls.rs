@@ -0,0 +1,41 @@
+//! Mutual TLS for the control API. The guest presents the certificate from run.json and only
+//! accepts clients whose certificate is signed by the configured CA.
+
+use super::config::TlsConfig;
+use anyhow::{anyhow, Context, Error};
+use rustls_pemfile::{certs, private_key};
+use std::sync::Arc;
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+pub fn acceptor(config: &TlsConfig) -> Result<TlsAcceptor, Error> {
+    let cert_chain = certs(&mut config.cert.as_bytes())
+        .collect::<Result<Vec<_>, _>>()
+        .context("reading tls.cert")?;
+    if cert_chain.is_empty() {
+        return Err(anyhow!("tls.cert contains no certificates"));
+    }
+    let key = private_key(&mut config.key.as_bytes())
+        .context("reading tls.key")?
+        .ok_or_else(|| anyhow!("tls.key contains no private key"))?;
+
+    let mut roots = RootCertStore::empty();
+    for ca in certs(&mut config.client_ca.as_bytes()) {
+        roots
+            .add(ca.context("reading tls.client_ca")?)
+            .context("adding tls.client_ca")?;
+    }
+    if roots.is_empty() {
+        return Err(anyhow!("tls.client_ca contains no certificates"));
+    }
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+        .build()
+        .context("building client certificate verifier")?;
+
+    let server_config = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(cert_chain, key)
+        .context("loading tls.cert and tls.key")?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
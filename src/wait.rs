@@ -0,0 +1,69 @@
+//! `/v1/wait`: blocking until a supervised process exits, for VMs that run one job to
+//! completion.
+
+use super::logs;
+use super::reaper::Rusage;
+use super::state::SharedState;
+use super::ApiError;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Lines of captured output returned with the exit status by default.
+const DEFAULT_LINES: usize = 20;
+
+#[derive(Deserialize, Debug)]
+pub struct WaitQuery {
+    /// The entrypoint, or a sidecar's name.
+    name: Option<String>,
+    /// Give up after this long, with a 503. Waits indefinitely otherwise.
+    timeout_ms: Option<u64>,
+    /// How many of the last lines of output to return.
+    lines: Option<usize>,
+}
+
+#[derive(Serialize, Debug)]
+struct WaitResponse {
+    name: String,
+    exit_code: Option<i32>,
+    signal: Option<i32>,
+    rusage: Rusage,
+    /// The last lines the process wrote; null when its output wasn't captured.
+    output: Option<String>,
+}
+
+/// Waits for the named process to exit and returns how it exited. A process that already
+/// exited (and wasn't restarted) returns straight away.
+pub async fn handle_wait(
+    query: WaitQuery,
+    state: SharedState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    info!("wait: {:?}", query);
+    let name = query.name.unwrap_or_else(|| "entrypoint".to_string());
+    let mut rx = state.watch_exit(&name).ok_or_else(|| {
+        warp::reject::custom(ApiError::NotFound(format!("{} was never started", name)))
+    })?;
+
+    let exited = rx.wait_for(Option::is_some);
+    let exit = match query.timeout_ms.map(Duration::from_millis) {
+        Some(timeout) => tokio::time::timeout(timeout, exited).await.map_err(|_| {
+            warp::reject::custom(ApiError::Unavailable(format!(
+                "{} was still running after {:?}",
+                name, timeout
+            )))
+        })?,
+        None => exited.await,
+    }
+    .map_err(|_| warp::reject::custom(ApiError::Internal(format!("lost track of {}", name))))?
+    .clone()
+    .expect("waited for an exit");
+
+    let output = logs::last_lines(&name, query.lines.unwrap_or(DEFAULT_LINES));
+    Ok(warp::reply::json(&WaitResponse {
+        name,
+        exit_code: exit.code,
+        signal: exit.signal,
+        rusage: exit.rusage,
+        output,
+    }))
+}